@@ -1,23 +1,35 @@
 //! HTTP constants
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+use std::convert::TryFrom;
 
 /// HTTP methods
 #[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
 pub enum Method {
     GET,
     POST,
     PUT,
     DELETE,
-    PATCH
+    PATCH,
+    OPTIONS,
+    HEAD,
+    /// Any method not covered by other variants, e.g. `PROPFIND`.
+    Other(String)
 }
 
 impl Method {
-    fn value(&self) -> &'static str {
+    pub(crate) fn value(&self) -> &str {
         match self {
             Method::GET => "GET",
             Method::POST => "POST",
             Method::PUT => "PUT",
             Method::DELETE => "DELETE",
-            Method::PATCH => "PATCH"
+            Method::PATCH => "PATCH",
+            Method::OPTIONS => "OPTIONS",
+            Method::HEAD => "HEAD",
+            Method::Other(method) => method
         }
     }
 
@@ -31,8 +43,61 @@ impl Method {
     pub fn equal(&self, value: &str) -> bool {
         self.value() == value
     }
+
+    fn known(value: &str) -> Option<Method> {
+        match value.to_ascii_uppercase().as_str() {
+            "GET" => Some(Method::GET),
+            "POST" => Some(Method::POST),
+            "PUT" => Some(Method::PUT),
+            "DELETE" => Some(Method::DELETE),
+            "PATCH" => Some(Method::PATCH),
+            "OPTIONS" => Some(Method::OPTIONS),
+            "HEAD" => Some(Method::HEAD),
+            _ => None
+        }
+    }
+}
+
+impl<'a> From<&'a str> for Method {
+    /// Converts method name to `Method`. Known methods are matched case-insensitively, like when
+    /// parsing. Names not covered by other variants are converted to `Method::Other`.
+    /// ```
+    /// # use http_test_server::http::Method;
+    /// assert_eq!(Method::from("post"), Method::POST);
+    /// assert_eq!(Method::from("PROPFIND"), Method::Other(String::from("PROPFIND")));
+    /// ```
+    fn from(value: &'a str) -> Method {
+        Method::known(value).unwrap_or_else(|| Method::Other(String::from(value)))
+    }
 }
 
+impl FromStr for Method {
+    type Err = ParseMethodError;
+
+    /// Parses method name. Known methods are matched case-insensitively, unknown names are
+    /// an error. Use `Method::from` or `Method::Other` for methods like `PROPFIND`.
+    /// ```
+    /// # use http_test_server::http::Method;
+    /// assert_eq!("get".parse::<Method>(), Ok(Method::GET));
+    /// assert!("PROPFIND".parse::<Method>().is_err());
+    /// ```
+    fn from_str(value: &str) -> Result<Method, ParseMethodError> {
+        Method::known(value).ok_or_else(|| ParseMethodError(String::from(value)))
+    }
+}
+
+/// Error returned when parsing an invalid method name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseMethodError(String);
+
+impl fmt::Display for ParseMethodError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid HTTP method '{}'", self.0)
+    }
+}
+
+impl Error for ParseMethodError {}
+
 /// HTTP status
 ///
 /// Can be converted to its numeral equivalent.
@@ -42,11 +107,13 @@ impl Method {
 ///
 /// assert_eq!(status_code, 404);
 /// ```
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
 pub enum Status {
     Continue = 100,
     SwitchingProtocols = 101,
     Processing = 102,
+    EarlyHints = 103,
     OK = 200,
     Created = 201,
     Accepted = 202,
@@ -55,6 +122,8 @@ pub enum Status {
     ResetContent = 205,
     PartialContent = 206,
     MultiStatus= 207,
+    AlreadyReported = 208,
+    ImUsed = 226,
     MultipleChoices= 300,
     MovedPermanently = 301,
     Found = 302,
@@ -82,23 +151,50 @@ pub enum Status {
     RangeNotSatisfiable = 416,
     ExpectationFailed = 417,
     ImATeapot = 418,
+    MisdirectedRequest = 421,
     UnprocessableEntity= 422,
     Locked = 423,
     FailedDependency = 424,
+    TooEarly = 425,
     UpgradeRequired = 426,
     PreconditionRequired   = 428,
     TooManyRequests = 429,
     RequestHeaderFieldsTooLarge = 431,
+    UnavailableForLegalReasons = 451,
     InternalServerError = 500,
     NotImplemented = 501,
     BadGateway = 502,
     ServiceUnavailable = 503,
     GatewayTimeout = 504,
     HttpVersionNotSupported = 505,
+    VariantAlsoNegotiates = 506,
     InsufficientStorage = 507,
+    LoopDetected = 508,
+    NotExtended = 510,
     NetworkAuthenticationRequired = 511
 }
 
+const STATUSES: [Status; 62] = [
+    Status::Continue, Status::SwitchingProtocols, Status::Processing, Status::EarlyHints,
+    Status::OK, Status::Created, Status::Accepted, Status::NonAuthoritativeInformation,
+    Status::NoContent, Status::ResetContent, Status::PartialContent, Status::MultiStatus,
+    Status::AlreadyReported, Status::ImUsed, Status::MultipleChoices, Status::MovedPermanently,
+    Status::Found, Status::SeeOther, Status::NotModified, Status::UseProxy,
+    Status::TemporaryRedirect, Status::PermanentRedirect, Status::BadRequest, Status::Unauthorized,
+    Status::PaymentRequired, Status::Forbidden, Status::NotFound, Status::MethodNotAllowed,
+    Status::NotAcceptable, Status::ProxyAuthenticationRequired, Status::RequestTimeout,
+    Status::Conflict, Status::Gone, Status::LengthRequired, Status::PreconditionFailed,
+    Status::PayloadTooLarge, Status::UriTooLong, Status::UnsupportedMediaType,
+    Status::RangeNotSatisfiable, Status::ExpectationFailed, Status::ImATeapot,
+    Status::MisdirectedRequest, Status::UnprocessableEntity, Status::Locked,
+    Status::FailedDependency, Status::TooEarly, Status::UpgradeRequired,
+    Status::PreconditionRequired, Status::TooManyRequests, Status::RequestHeaderFieldsTooLarge,
+    Status::UnavailableForLegalReasons, Status::InternalServerError, Status::NotImplemented,
+    Status::BadGateway, Status::ServiceUnavailable, Status::GatewayTimeout,
+    Status::HttpVersionNotSupported, Status::VariantAlsoNegotiates, Status::InsufficientStorage,
+    Status::LoopDetected, Status::NotExtended, Status::NetworkAuthenticationRequired
+];
+
 impl Status {
     /// Returns status' full description
     /// ```
@@ -113,6 +209,7 @@ impl Status {
             Status::Continue => "100 Continue",
             Status::SwitchingProtocols => "101 Switching Protocols",
             Status::Processing => "102 Processing",
+            Status::EarlyHints => "103 Early Hints",
             Status::OK => "200 Ok",
             Status::Created => "201 Created",
             Status::Accepted => "202 Accepted",
@@ -121,6 +218,8 @@ impl Status {
             Status::ResetContent => "205 Reset Content",
             Status::PartialContent => "206 Partial Content",
             Status::MultiStatus=> "207 Multi Status",
+            Status::AlreadyReported => "208 Already Reported",
+            Status::ImUsed => "226 IM Used",
             Status::MultipleChoices=> "300 Multiple Choices",
             Status::MovedPermanently => "301 Moved Permanently",
             Status::Found => "302 Found",
@@ -148,22 +247,134 @@ impl Status {
             Status::RangeNotSatisfiable => "416 Range Not Satisfiable",
             Status::ExpectationFailed => "417 Expectation Failed",
             Status::ImATeapot => "418 I'm A Teapot",
+            Status::MisdirectedRequest => "421 Misdirected Request",
             Status::UnprocessableEntity=> "422 Unprocessable Entity",
             Status::Locked => "423 Locked",
             Status::FailedDependency => "424 Failed Dependency",
+            Status::TooEarly => "425 Too Early",
             Status::UpgradeRequired => "426 Upgrade Required",
             Status::PreconditionRequired   => "428 Precondition Required",
             Status::TooManyRequests => "429 Too Many Requests",
             Status::RequestHeaderFieldsTooLarge => "431 Request Header Fields Too Large",
+            Status::UnavailableForLegalReasons => "451 Unavailable For Legal Reasons",
             Status::InternalServerError => "500 Internal Server Error",
             Status::NotImplemented => "501 Not Implemented",
             Status::BadGateway => "502 Bad Gateway",
             Status::ServiceUnavailable => "503 Service Unavailable",
             Status::GatewayTimeout => "504 Gateway Timeout",
             Status::HttpVersionNotSupported => "505 Http Version Not Supported",
+            Status::VariantAlsoNegotiates => "506 Variant Also Negotiates",
             Status::InsufficientStorage => "507 Insufficient Storage",
+            Status::LoopDetected => "508 Loop Detected",
+            Status::NotExtended => "510 Not Extended",
             Status::NetworkAuthenticationRequired => "511 Network Authentication Required",
         }
     }
+
+    /// Returns status' numeric code
+    /// ```
+    /// use http_test_server::http::Status;
+    ///
+    /// assert_eq!(Status::NotFound.code(), 404);
+    /// ```
+    pub fn code(&self) -> u16 {
+        *self as u16
+    }
+
+    /// Returns status for given code. `None` when code is not covered by `Status`.
+    /// ```
+    /// use http_test_server::http::Status;
+    ///
+    /// assert_eq!(Status::from_code(404), Some(Status::NotFound));
+    /// assert_eq!(Status::from_code(333), None);
+    /// ```
+    pub fn from_code(code: u16) -> Option<Status> {
+        STATUSES.iter().find(|status| status.code() == code).cloned()
+    }
+
+    /// Returns full description of status with given code, e.g. `"404 Not Found"`.
+    /// `None` when code is not covered by `Status`.
+    /// ```
+    /// use http_test_server::http::Status;
+    ///
+    /// assert_eq!(Status::description_for(404), Some("404 Not Found"));
+    /// assert_eq!(Status::description_for(333), None);
+    /// ```
+    pub fn description_for(code: u16) -> Option<&'static str> {
+        Status::from_code(code).map(|status| status.description())
+    }
+}
+
+/// Formats status as its full description, same as [`Status::description`].
+///
+/// ```
+/// use http_test_server::http::Status;
+///
+/// assert_eq!(Status::OK.to_string(), "200 Ok");
+/// ```
+/// [`Status::description`]: enum.Status.html#method.description
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+impl TryFrom<u16> for Status {
+    type Error = UnknownStatusError;
+
+    /// Converts code into `Status`. Fails when code is not covered by `Status`.
+    /// ```
+    /// use http_test_server::http::Status;
+    /// use std::convert::TryFrom;
+    ///
+    /// assert_eq!(Status::try_from(451), Ok(Status::UnavailableForLegalReasons));
+    /// assert!(Status::try_from(333).is_err());
+    /// ```
+    fn try_from(code: u16) -> Result<Status, UnknownStatusError> {
+        Status::from_code(code).ok_or(UnknownStatusError(code))
+    }
 }
 
+/// Error returned when converting a code not covered by [`Status`].
+///
+/// [`Status`]: enum.Status.html
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnknownStatusError(u16);
+
+impl fmt::Display for UnknownStatusError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Unknown HTTP status code '{}'", self.0)
+    }
+}
+
+impl Error for UnknownStatusError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_parse_known_methods_regardless_of_case() {
+        for name in &["GET", "get", "Post", "put", "DELETE", "patch", "options", "Head"] {
+            let method = name.parse::<Method>().unwrap();
+
+            assert!(method.equal(&name.to_ascii_uppercase()));
+            assert_eq!(Method::from(*name), method);
+        }
+    }
+
+    #[test]
+    fn should_fail_to_parse_unknown_methods() {
+        assert_eq!("PROPFIND".parse::<Method>(), Err(ParseMethodError(String::from("PROPFIND"))));
+        assert!("".parse::<Method>().is_err());
+        assert!("NOT A METHOD".parse::<Method>().is_err());
+    }
+
+    #[test]
+    fn should_convert_unknown_methods_to_other() {
+        let method = Method::from("PROPFIND");
+
+        assert_eq!(method, Method::Other(String::from("PROPFIND")));
+        assert!(method.equal("PROPFIND"));
+    }
+}
@@ -6,7 +6,7 @@
 //! - Allows multiple endpoints and simultaneous client connections
 //! - Streaming support
 //! - Helper functions to retrieve data such as request count, number of connected clients and
-//! requests metadata
+//!   requests metadata
 //! - Automatically allocates free port and close server after use
 //!
 //! # Examples:
@@ -31,6 +31,7 @@
 //! // request: POST /some-endpoint/new
 //!
 //! // HTTP/1.1 201 Created\r\n
+//! // Connection: close\r\n
 //! // Content-Type: application/json\r\n
 //! // Cache-Control: no-cache\r\n
 //! // \r\n
@@ -58,8 +59,10 @@
 //! // request: GET /user/abc123?filter=all
 //!
 //! // HTTP/1.1 200 Ok\r\n
+//! // Connection: close\r\n
 //! // Content-Type: application/json\r\n
 //! // Cache-Control: no-cache\r\n
+//! // Content-Length: 35\r\n
 //! // \r\n
 //! // { "id": "abc123", "filter": "all" }
 //!
@@ -118,7 +121,9 @@
 //! // request: GET /original
 //!
 //! // HTTP/1.1 303 See Other\r\n
+//! // Connection: close\r\n
 //! // Location: /new\r\n
+//! // Content-Length: 0\r\n
 //! // \r\n
 //!
 //!
@@ -135,6 +140,8 @@
 //! // request: GET /hello/8/b/doesntmatter-hehe
 //!
 //! // HTTP/1.1 200 Ok\r\n
+//! // Connection: close\r\n
+//! // Content-Length: 0\r\n
 //! // \r\n
 //!
 //! ```
@@ -146,35 +153,86 @@
 //! As this crate was devised to be used in tests, smart behaviours could be confusing and misleading. Having said that, for the sake of convenience, some default behaviours were implemented:
 //!
 //! - Server returns `404 Not Found` when requested resource was not configured.
-//! - Server returns `405 Method Not Allowed` when trying to reach resource with different method from those configured.
+//! - Server returns `405 Method Not Allowed`, with an `Allow` header, when trying to reach resource with different method from those configured.
+//! - Server returns `501 Not Implemented` to unknown methods, unless a resource handles them.
 //! - When a resource is created it responds to `GET` with `200 Ok` by default.
+//! - Connections are closed after the response, with a `Connection: close` header, unless resource is a stream or defines its own `Connection` header.
 extern crate regex;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "serde")]
+extern crate serde_json;
+#[cfg(feature = "brotli")]
+extern crate brotli;
 
 pub mod resource;
 pub mod http;
 
 use std::thread;
+use std::thread::JoinHandle;
 use std::net::TcpListener;
 use std::net::TcpStream;
+use std::net::SocketAddr;
+use std::net::ToSocketAddrs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::net::Shutdown;
 use std::io::prelude::*;
-use std::io::Error;
+use std::io::{Error, ErrorKind};
 use std::io::BufReader;
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::sync::mpsc;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
 use std::collections::HashMap;
+use std::time::{Duration, Instant};
 use http::Method;
 use http::Status;
 pub use resource::Resource;
+use resource::SlowClientPolicy;
+use resource::InvalidUriError;
+use resource::{splitmix64, time_seed};
+use regex::Regex;
+
+#[cfg(feature = "serde")]
+use std::fs;
+#[cfg(feature = "serde")]
+use std::path::{Path, PathBuf};
+#[cfg(feature = "serde")]
+use serde::Serialize;
 
 type ServerResources = Arc<Mutex<Vec<Resource>>>;
-type RequestsTX = Arc<Mutex<Option<mpsc::Sender<Request>>>>;
+type RequestsTX = Arc<Mutex<Vec<mpsc::Sender<Request>>>>;
+type SharedConfig = Arc<ServerConfig>;
 
 /// Controls the listener life cycle and creates new resources
 pub struct TestServer {
-    port: u16,
+    address: SocketAddr,
     resources: ServerResources,
-    requests_tx: RequestsTX
+    requests_tx: RequestsTX,
+    config: SharedConfig,
+    listener_thread: Option<JoinHandle<()>>
+}
+
+#[derive(Default)]
+struct ServerConfig {
+    closed: AtomicBool,
+    tcp_nodelay: AtomicBool,
+    serialized: AtomicBool,
+    keep_alive_timeout: Mutex<Option<Duration>>,
+    read_timeout: Mutex<Option<Duration>>,
+    keep_alive_max: Mutex<Option<usize>>,
+    default_delay: Mutex<Option<Duration>>,
+    delay_random: Mutex<u64>,
+    ignored_paths: Mutex<Vec<(Regex, Status)>>,
+    active_connections: AtomicUsize,
+    total_request_count: AtomicU32,
+    unmatched_request_count: AtomicU32,
+    not_found_resource: Mutex<Option<Resource>>,
+    method_not_allowed_body: Mutex<Option<String>>,
+    #[cfg(feature = "serde")]
+    record_dir: Mutex<Option<PathBuf>>,
+    #[cfg(feature = "serde")]
+    record_count: AtomicUsize
 }
 
 impl TestServer {
@@ -201,30 +259,76 @@ impl TestServer {
     ///
     /// ```
     pub fn new_with_port(port: u16) -> Result<TestServer, Error> {
-        let listener = TcpListener::bind(format!("127.0.0.1:{}", port)).unwrap();
-        let port = listener.local_addr()?.port();
+        TestServer::new_with_addr(format!("127.0.0.1:{}", port))
+    }
+
+    /// Same behaviour as `new`, but always binds to IPv4 loopback address (`127.0.0.1`).
+    /// ```
+    ///# extern crate http_test_server;
+    ///# use http_test_server::{TestServer, Resource};
+    /// let server = TestServer::new_ipv4().unwrap();
+    ///
+    /// assert!(server.socket_addr().is_ipv4());
+    /// ```
+    pub fn new_ipv4() -> Result<TestServer, Error> {
+        TestServer::new_with_addr("127.0.0.1:0")
+    }
+
+    /// Same behaviour as `new`, but binds to IPv6 loopback address (`::1`).
+    /// ```no_run
+    ///# extern crate http_test_server;
+    ///# use http_test_server::{TestServer, Resource};
+    /// let server = TestServer::new_ipv6().unwrap();
+    ///
+    /// assert!(server.socket_addr().is_ipv6());
+    /// ```
+    pub fn new_ipv6() -> Result<TestServer, Error> {
+        TestServer::new_with_addr("[::1]:0")
+    }
+
+    /// Same behaviour as `new`, but binds to given address. Useful to listen on other interfaces,
+    /// e.g. `0.0.0.0`, making server reachable from other hosts or containers.
+    /// ```
+    ///# extern crate http_test_server;
+    ///# use http_test_server::{TestServer, Resource};
+    /// let server = TestServer::new_with_addr("127.0.0.1:0").unwrap();
+    ///
+    /// assert_eq!(server.address().ip().to_string(), "127.0.0.1");
+    /// ```
+    pub fn new_with_addr(address: impl ToSocketAddrs) -> Result<TestServer, Error> {
+        let listener = TcpListener::bind(address)?;
+        let address = listener.local_addr()?;
         let resources: ServerResources = Arc::new(Mutex::new(vec!()));
-        let requests_tx = Arc::new(Mutex::new(None));
+        let requests_tx = Arc::new(Mutex::new(vec!()));
+        let config: SharedConfig = Arc::new(ServerConfig {
+            delay_random: Mutex::new(time_seed()),
+            ..ServerConfig::default()
+        });
 
         let res = Arc::clone(&resources);
         let tx = Arc::clone(&requests_tx);
+        let conf = Arc::clone(&config);
 
-        thread::spawn(move || {
+        let listener_thread = thread::spawn(move || {
             for stream in listener.incoming() {
-                let stream = stream.unwrap();
-
-                let mut buffer = [0; 512];
-                stream.peek(&mut buffer).unwrap();
-
-                if buffer.starts_with(b"CLOSE") {
+                if conf.closed.load(Ordering::SeqCst) {
                     break;
                 }
 
-                handle_connection(&stream, res.clone(), tx.clone());
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue
+                };
+
+                let handler = handle_connection(stream, res.clone(), tx.clone(), conf.clone());
+
+                if conf.serialized.load(Ordering::Relaxed) {
+                    let _ = handler.join();
+                }
             }
         });
 
-        Ok(TestServer{ port, resources, requests_tx })
+        Ok(TestServer{ address, resources, requests_tx, config, listener_thread: Some(listener_thread) })
     }
 
     /// Returns associated port number.
@@ -236,422 +340,3157 @@ impl TestServer {
     /// assert!(server.port() > 0);
     /// ```
     pub fn port(&self) -> u16 {
-       self.port
+       self.address.port()
     }
 
-    /// Closes listener. Server stops receiving connections. Do nothing if listener is already closed.
+    /// Returns address listener is bound to, including IP and port.
     ///
-    /// In most the cases this method is not required as the listener is automatically closed when
-    /// the value is dropped.
+    /// Useful to build URLs without assuming `localhost` resolves to the right address family.
+    /// ```
+    ///# extern crate http_test_server;
+    ///# use http_test_server::{TestServer, Resource};
+    /// let server = TestServer::new().unwrap();
     ///
+    /// assert!(server.address().ip().is_loopback());
+    /// ```
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Returns address listener is bound to. Same as [`address`].
     /// ```
     ///# extern crate http_test_server;
     ///# use http_test_server::{TestServer, Resource};
     /// let server = TestServer::new().unwrap();
     ///
-    /// server.close();
+    /// assert!(server.socket_addr().ip().is_loopback());
     /// ```
-    pub fn close(&self) {
-        if let Ok(mut stream) = TcpStream::connect(format!("127.0.0.1:{}", self.port)) {
-            stream.write_all(b"CLOSE").unwrap();
-            stream.flush().unwrap();
-        }
+    /// [`address`]: struct.TestServer.html#method.address
+    pub fn socket_addr(&self) -> SocketAddr {
+        self.address()
     }
 
-    /// Creates a new resource. By default resources answer "200 Ok".
+    /// Returns number of connections currently open.
+    /// ```
+    ///# extern crate http_test_server;
+    ///# use http_test_server::{TestServer, Resource};
+    /// let server = TestServer::new().unwrap();
     ///
-    /// Check [`Resource`] for all possible configurations.
+    /// assert_eq!(server.active_connections(), 0);
+    /// ```
+    pub fn active_connections(&self) -> usize {
+        self.config.active_connections.load(Ordering::SeqCst)
+    }
+
+    /// Enables or disables `TCP_NODELAY` on accepted connections.
+    ///
+    /// When enabled, Nagle's algorithm is disabled and small writes, like stream messages,
+    /// are sent straight away instead of being batched.
     ///
     /// ```
     ///# extern crate http_test_server;
     ///# use http_test_server::{TestServer, Resource};
     /// let server = TestServer::new().unwrap();
-    /// let resource = server.create_resource("/user/settings");
+    ///
+    /// server.tcp_nodelay(true);
     /// ```
-    /// [`Resource`]: struct.Resource.html
-    pub fn create_resource(&self, uri: &str) -> Resource {
-        let mut resources = self.resources.lock().unwrap();
-        let resource = Resource::new(uri);
-
-        resources.push(resource.clone());
+    pub fn tcp_nodelay(&self, enabled: bool) -> &TestServer {
+        self.config.tcp_nodelay.store(enabled, Ordering::Relaxed);
 
-        resource
+        self
     }
 
-    /// Retrieves information on new requests.
+    /// Handles connections one at a time, in the order they were accepted.
     ///
-    /// ```no_run
+    /// Requests are recorded in the same order clients sent them. A connection is only accepted
+    /// after the previous one was handled, so open streams block other clients.
+    ///
+    /// ```
     ///# extern crate http_test_server;
     ///# use http_test_server::{TestServer, Resource};
-    ///# use std::collections::HashMap;
     /// let server = TestServer::new().unwrap();
     ///
-    /// for request in server.requests().iter() {
-    ///     assert_eq!(request.url, "/endpoint");
-    ///     assert_eq!(request.method, "GET");
-    ///     assert_eq!(request.headers.get("Content-Type").unwrap(), "text");
-    /// }
+    /// server.serialized();
     /// ```
-    pub fn requests(&self) -> mpsc::Receiver<Request> {
-        let (tx, rx) = mpsc::channel();
+    pub fn serialized(&self) -> &TestServer {
+        self.config.serialized.store(true, Ordering::Relaxed);
 
-        *self.requests_tx.lock().unwrap() = Some(tx);
-
-        rx
+        self
     }
-}
 
-impl Drop for TestServer {
-    fn drop(&mut self) {
-        self.close();
-    }
-}
+    /// Closes connections that stay idle longer than given timeout.
+    ///
+    /// A connection is idle while the server waits for a request on it.
+    ///
+    /// ```
+    ///# extern crate http_test_server;
+    ///# use http_test_server::{TestServer, Resource};
+    /// use std::time::Duration;
+    /// let server = TestServer::new().unwrap();
+    ///
+    /// server.keep_alive_timeout(Duration::from_secs(1));
+    /// ```
+    pub fn keep_alive_timeout(&self, timeout: Duration) -> &TestServer {
+        *self.config.keep_alive_timeout.lock().unwrap() = Some(timeout);
 
-fn handle_connection(stream: &TcpStream, resources: ServerResources, requests_tx: RequestsTX) {
-    let stream = stream.try_clone().unwrap();
+        self
+    }
 
-    thread::spawn(move || {
-        let mut write_stream = stream.try_clone().unwrap();
-        let mut reader = BufReader::new(stream);
+    /// Closes connections that take longer than given timeout to send a request.
+    ///
+    /// Applies to every read while receiving a request. Connections are closed without response.
+    ///
+    /// ```
+    ///# extern crate http_test_server;
+    ///# use http_test_server::{TestServer, Resource};
+    /// use std::time::Duration;
+    /// let server = TestServer::new().unwrap();
+    ///
+    /// server.set_read_timeout(Duration::from_secs(1));
+    /// ```
+    pub fn set_read_timeout(&self, timeout: Duration) -> &TestServer {
+        *self.config.read_timeout.lock().unwrap() = Some(timeout);
 
-        let (method, url) = parse_request_header(&mut reader);
-        let resource = find_resource(method.clone(), url.clone(), resources);
+        self
+    }
 
-        if let Some(delay) = resource.get_delay() {
-            thread::sleep(delay);
-        }
+    /// Keeps connections open to serve multiple requests each, until client sends
+    /// `Connection: close`, closes the connection or stays idle longer than [`keep_alive_timeout`].
+    ///
+    /// Same as [`keep_alive_max`] without limit.
+    /// ```
+    ///# extern crate http_test_server;
+    ///# use http_test_server::{TestServer, Resource};
+    /// let server = TestServer::new().unwrap();
+    ///
+    /// server.keep_alive();
+    /// ```
+    /// [`keep_alive_timeout`]: struct.TestServer.html#method.keep_alive_timeout
+    /// [`keep_alive_max`]: struct.TestServer.html#method.keep_alive_max
+    pub fn keep_alive(&self) -> &TestServer {
+        *self.config.keep_alive_max.lock().unwrap() = Some(usize::MAX);
 
-        write_stream.write_all(resource.build_response(&url).as_bytes()).unwrap();
-        write_stream.flush().unwrap();
+        self
+    }
 
-        if let Some(ref tx) = *requests_tx.lock().unwrap() {
-            let mut headers = HashMap::new();
+    /// Keeps connections open to serve up to `max` requests each.
+    ///
+    /// The last response in a connection contains `Connection: close` header and the connection
+    /// is closed afterwards. Connections are also closed when client sends `Connection: close`
+    /// or stays idle longer than [`keep_alive_timeout`].
+    /// ```
+    ///# extern crate http_test_server;
+    ///# use http_test_server::{TestServer, Resource};
+    /// let server = TestServer::new().unwrap();
+    ///
+    /// server.keep_alive_max(2);
+    /// ```
+    /// [`keep_alive_timeout`]: struct.TestServer.html#method.keep_alive_timeout
+    pub fn keep_alive_max(&self, max: usize) -> &TestServer {
+        if max == 0 {
+            panic!("Keep-alive max must be greater than zero");
+        }
 
-            for line in reader.lines() {
-                let line = line.unwrap();
+        *self.config.keep_alive_max.lock().unwrap() = Some(max);
 
-                if line == "" {
-                    break
-                }
+        self
+    }
 
-                let (name, value) = parse_header(line);
-                headers.insert(name, value);
-            }
+    /// Defines body of `405 Method Not Allowed` responses.
+    ///
+    /// These responses always include an `Allow` header listing methods configured for the URI.
+    /// ```
+    ///# extern crate http_test_server;
+    ///# use http_test_server::{TestServer, Resource};
+    ///# use http_test_server::http::Method;
+    /// let server = TestServer::new().unwrap();
+    /// server.create_resource("/user").method(Method::POST);
+    ///
+    /// server.method_not_allowed_body(r#"{"error":"method not allowed"}"#);
+    ///
+    /// // request: GET /user
+    ///
+    /// // HTTP/1.1 405 Method Not Allowed\r\n
+    /// // Allow: POST\r\n
+    /// // Content-Length: 30\r\n
+    /// // \r\n
+    /// // {"error":"method not allowed"}
+    /// ```
+    pub fn method_not_allowed_body(&self, body: &str) -> &TestServer {
+        *self.config.method_not_allowed_body.lock().unwrap() = Some(String::from(body));
 
-            tx.send(Request { url, method, headers }).unwrap();
-        }
+        self
+    }
 
-        if resource.is_stream() {
-            let receiver = resource.stream_receiver();
-            for line in receiver.iter() {
-                write_stream.write_all(line.as_bytes()).unwrap();
-                write_stream.flush().unwrap();
-            }
-        }
+    /// Defines delay applied to every response, unless resource defines its own delay.
+    ///
+    /// See also: [`Resource::delay`].
+    /// ```
+    ///# extern crate http_test_server;
+    ///# use http_test_server::{TestServer, Resource};
+    /// use std::time::Duration;
+    /// let server = TestServer::new().unwrap();
+    ///
+    /// server.default_delay(Duration::from_millis(100));
+    /// ```
+    /// [`Resource::delay`]: struct.Resource.html#method.delay
+    pub fn default_delay(&self, delay: Duration) -> &TestServer {
+        *self.config.default_delay.lock().unwrap() = Some(delay);
 
-    });
-}
+        self
+    }
 
-fn parse_header(message: String) -> (String, String) {
-    let parts: Vec<&str> = message.splitn(2, ':').collect();
-    (String::from(parts[0]), String::from(parts[1].trim()))
-}
+    /// Seeds random generator used by [`Resource::delay_range`], so the same sequence of delays
+    /// is picked on every run. Generator is shared by all resources of this server.
+    /// ```
+    ///# extern crate http_test_server;
+    ///# use http_test_server::{TestServer, Resource};
+    /// use std::time::Duration;
+    /// let server = TestServer::new().unwrap();
+    ///
+    /// server.delay_range_seeded(42);
+    /// server.create_resource("/slow").delay_range(Duration::from_millis(100), Duration::from_millis(500));
+    /// ```
+    /// [`Resource::delay_range`]: struct.Resource.html#method.delay_range
+    pub fn delay_range_seeded(&self, seed: u64) -> &TestServer {
+        *self.config.delay_random.lock().unwrap() = seed;
 
-fn parse_request_header(reader: &mut dyn BufRead) -> (String, String) {
-    let mut request_header = String::from("");
-    reader.read_line(&mut request_header).unwrap();
+        self
+    }
 
-    let request_header: Vec<&str> = request_header
-        .split_whitespace().collect();
+    /// Responds `204 No Content` to requests to matching paths, like `/favicon.ico`.
+    ///
+    /// Ignored requests are not matched against resources nor sent to [`requests`] receivers.
+    /// `*` matches any sequence of characters. Query string is not considered.
+    ///
+    /// See also: [`ignore_path_with_status`].
+    /// ```
+    ///# extern crate http_test_server;
+    ///# use http_test_server::{TestServer, Resource};
+    /// let server = TestServer::new().unwrap();
+    ///
+    /// server
+    ///     .ignore_path("/favicon.ico")
+    ///     .ignore_path("/.well-known/*");
+    /// ```
+    /// [`requests`]: struct.TestServer.html#method.requests
+    /// [`ignore_path_with_status`]: struct.TestServer.html#method.ignore_path_with_status
+    pub fn ignore_path(&self, pattern: &str) -> &TestServer {
+        self.ignore_path_with_status(pattern, Status::NoContent)
+    }
 
-    (request_header[0].to_string(), request_header[1].to_string())
-}
+    /// Same as [`ignore_path`], responding with given status.
+    /// ```
+    ///# extern crate http_test_server;
+    ///# use http_test_server::{TestServer, Resource};
+    /// use http_test_server::http::Status;
+    /// let server = TestServer::new().unwrap();
+    ///
+    /// server.ignore_path_with_status("/favicon.ico", Status::NotFound);
+    /// ```
+    /// [`ignore_path`]: struct.TestServer.html#method.ignore_path
+    pub fn ignore_path_with_status(&self, pattern: &str, status: Status) -> &TestServer {
+        let pattern = pattern.split('*').map(regex::escape).collect::<Vec<String>>().join(".*");
+        let regex = Regex::new(&format!("^{}$", pattern)).unwrap();
 
-fn find_resource(method: String, url: String, resources: ServerResources) -> Resource {
-    let resources = resources.lock().unwrap();
+        self.config.ignored_paths.lock().unwrap().push((regex, status));
 
-    match resources.iter().find(|r| r.matches_uri(&url) && r.get_method().equal(&method) ) {
-        Some(resource) => {
-            resource.increment_request_count();
-            resource.clone()
-        },
-        None => {
-            // resource not found, check whether to show 404 or MethodNotAllowed.
-            let resources_for_uri = resources.iter().filter(|r| r.matches_uri(&url));
-            if resources_for_uri.count() == 0 {
-                return Resource::new(&url).status(Status::NotFound).clone();
-            }
-            Resource::new(&url).status(Status::MethodNotAllowed).clone()
-        }
+        self
     }
-}
 
+    /// Records every request received as a JSON file in the given directory.
+    ///
+    /// Each file contains request's method, url, headers and body. Files are named after the
+    /// order requests were received, e.g. `request-0001.json`, `request-0002.json`.
+    /// Directory is created if it does not exist. Requests that can't be written are not recorded,
+    /// but still answered.
+    ///
+    /// _Requires `serde` feature._
+    ///
+    /// ```
+    ///# extern crate http_test_server;
+    ///# use http_test_server::{TestServer, Resource};
+    /// let server = TestServer::new().unwrap();
+    ///
+    /// server.record_to_dir("target/recorded-requests");
+    /// ```
+    #[cfg(feature = "serde")]
+    pub fn record_to_dir<P: AsRef<Path>>(&self, path: P) -> &TestServer {
+        *self.config.record_dir.lock().unwrap() = Some(path.as_ref().to_path_buf());
 
-/// Request information
-///
-/// this contains basic information about a request received.
-#[derive(Debug, PartialEq)]
-pub struct Request {
-    /// Request URL
-    pub url: String,
-    /// HTTP method
-    pub method: String,
-    /// Request headers
-    pub headers: HashMap<String, String>
-}
+        self
+    }
 
-#[cfg(test)]
-mod tests {
-    use std::io::prelude::*;
-    use std::io::BufReader;
-    use std::io::ErrorKind;
-    use std::net::TcpStream;
-    use std::time::Duration;
-    use std::sync::mpsc;
-    use super::*;
+    /// Closes listener. Server stops receiving connections. Do nothing if listener is already closed.
+    ///
+    /// In most the cases this method is not required as the listener is automatically closed when
+    /// the value is dropped.
+    ///
+    /// ```
+    ///# extern crate http_test_server;
+    ///# use http_test_server::{TestServer, Resource};
+    /// let server = TestServer::new().unwrap();
+    ///
+    /// server.close();
+    /// ```
+    pub fn close(&self) {
+        let mut address = self.address;
+
+        // listener bound to all interfaces is reachable through loopback
+        if address.ip().is_unspecified() {
+            address.set_ip(match address {
+                SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST)
+            });
+        }
 
-    fn make_request(port: u16, uri: &str) -> TcpStream {
-       request(port, uri, "GET")
-    }
+        self.config.closed.store(true, Ordering::SeqCst);
 
-    fn make_post_request(port: u16, uri: &str) -> TcpStream {
-       request(port, uri, "POST")
+        // wakes listener up, so it notices it was closed
+        let _ = TcpStream::connect(address);
     }
 
-    fn request(port: u16, uri: &str, method: &str) -> TcpStream {
-        let host = format!("127.0.0.1:{}", port);
-        let mut stream = TcpStream::connect(host).unwrap();
-        let request = format!(
-            "{} {} HTTP/1.1\r\nContent-Type: text\r\n\r\n",
-            method,
-            uri
-        );
-
-        stream.write(request.as_bytes()).unwrap();
-        stream.flush().unwrap();
+    /// Closes listener and waits for it to stop. Unlike [`close`], listener is guaranteed to be
+    /// closed when this method returns.
+    ///
+    /// ```
+    ///# extern crate http_test_server;
+    ///# use http_test_server::{TestServer, Resource};
+    /// use std::net::TcpStream;
+    ///
+    /// let server = TestServer::new().unwrap();
+    /// let address = server.address();
+    ///
+    /// server.shutdown();
+    ///
+    /// assert!(TcpStream::connect(address).is_err());
+    /// ```
+    /// [`close`]: struct.TestServer.html#method.close
+    pub fn shutdown(mut self) {
+        self.close();
 
-        stream
+        if let Some(listener_thread) = self.listener_thread.take() {
+            let _ = listener_thread.join();
+        }
     }
 
-    #[test]
-    fn returns_404_when_requested_enexistent_resource() {
-        let server = TestServer::new().unwrap();
-        let stream = make_request(server.port(), "/something");
+    /// Creates a new resource. By default resources answer "200 Ok".
+    ///
+    /// Check [`Resource`] for all possible configurations.
+    ///
+    /// URI may contain path parameters: `{name}` matches a path segment, `{name?}` an optional
+    /// segment and `{name*}` the rest of the path, slashes included.
+    ///
+    /// Panics when URI is not a valid regex. See [`try_create_resource`].
+    ///
+    /// ```
+    ///# extern crate http_test_server;
+    ///# use http_test_server::{TestServer, Resource};
+    /// let server = TestServer::new().unwrap();
+    /// let resource = server.create_resource("/user/settings");
+    /// let files = server.create_resource("/static/{path*}");
+    /// ```
+    /// [`Resource`]: struct.Resource.html
+    /// [`try_create_resource`]: struct.TestServer.html#method.try_create_resource
+    pub fn create_resource(&self, uri: &str) -> Resource {
+        let mut resources = self.resources.lock().unwrap();
+        let resource = Resource::new(uri);
+
+        resources.push(resource.clone());
+
+        resource
+    }
+
+    /// Creates a new resource, failing when URI is not a valid regex.
+    ///
+    /// Error message contains the URI and the reason it is invalid.
+    ///
+    /// ```
+    ///# extern crate http_test_server;
+    ///# use http_test_server::{TestServer, Resource};
+    /// let server = TestServer::new().unwrap();
+    ///
+    /// assert!(server.try_create_resource("/user/[0-9]+").is_ok());
+    /// assert!(server.try_create_resource("/user/(unclosed").is_err());
+    /// ```
+    pub fn try_create_resource(&self, uri: &str) -> Result<Resource, InvalidUriError> {
+        let resource = Resource::try_new(uri)?;
+
+        self.resources.lock().unwrap().push(resource.clone());
+
+        Ok(resource)
+    }
+
+    /// Creates a new resource matching URI path literally. Characters like `.` and `+` are not
+    /// treated as regex and path must match exactly. Path parameters (`{param}`) and query
+    /// parameters work as in [`create_resource`].
+    ///
+    /// ```
+    ///# extern crate http_test_server;
+    ///# use http_test_server::{TestServer, Resource};
+    /// let server = TestServer::new().unwrap();
+    /// let resource = server.create_resource_literal("/files/{name}/report.pdf");
+    /// ```
+    /// [`create_resource`]: struct.TestServer.html#method.create_resource
+    pub fn create_resource_literal(&self, uri: &str) -> Resource {
+        let mut resources = self.resources.lock().unwrap();
+        let resource = Resource::new_literal(uri);
+
+        resources.push(resource.clone());
+
+        resource
+    }
+
+    /// Creates a new resource matching requests with given regex, used as is.
+    ///
+    /// Unlike [`create_resource`], `{param}` placeholders and query parameters are not
+    /// processed. The regex is matched against the whole request URL, query string included,
+    /// so query matching is up to the pattern. Named groups are available as path parameters.
+    ///
+    /// Panics when pattern is not a valid regex.
+    ///
+    /// ```
+    ///# extern crate http_test_server;
+    ///# use http_test_server::{TestServer, Resource};
+    /// let server = TestServer::new().unwrap();
+    /// let resource = server.create_resource_with_regex("^/(?<group>.*)/(?<artifact>[^/]+)/maven-metadata.xml$");
+    ///
+    /// resource.body("{path.group}:{path.artifact}");
+    /// ```
+    /// [`create_resource`]: struct.TestServer.html#method.create_resource
+    pub fn create_resource_with_regex(&self, pattern: &str) -> Resource {
+        let mut resources = self.resources.lock().unwrap();
+        let resource = Resource::new_regex(pattern);
+
+        resources.push(resource.clone());
+
+        resource
+    }
+
+    /// Creates a resource that responds with the request it received, as text.
+    ///
+    /// Response body contains request line, as sent by the client, headers (sorted by name) and
+    /// body. Responds to any method. Useful to debug what a client sends.
+    ///
+    /// ```
+    ///# extern crate http_test_server;
+    ///# use http_test_server::{TestServer, Resource};
+    /// use std::io::{Read, Write};
+    /// use std::net::TcpStream;
+    ///
+    /// let server = TestServer::new().unwrap();
+    /// server.create_echo_resource("/echo");
+    ///
+    /// let mut stream = TcpStream::connect(format!("127.0.0.1:{}", server.port())).unwrap();
+    /// stream.write_all(b"POST /echo HTTP/1.0\r\nContent-Length: 5\r\n\r\nhello").unwrap();
+    ///
+    /// let mut response = String::new();
+    /// stream.read_to_string(&mut response).unwrap();
+    ///
+    /// assert_eq!(response, "HTTP/1.1 200 Ok\r\n\
+    ///                       Connection: close\r\n\
+    ///                       Content-Type: text/plain\r\n\
+    ///                       Content-Length: 47\r\n\
+    ///                       \r\n\
+    ///                       POST /echo HTTP/1.0\r\n\
+    ///                       Content-Length: 5\r\n\
+    ///                       \r\n\
+    ///                       hello");
+    /// ```
+    pub fn create_echo_resource(&self, uri: &str) -> Resource {
+        let resource = self.create_resource(uri);
+
+        resource
+            .any_method()
+            .header("Content-Type", "text/plain")
+            .body_fn_req(|request| {
+                let mut headers: Vec<_> = request.headers.iter().collect();
+                headers.sort();
+
+                let headers: String = headers.iter()
+                    .map(|(name, value)| format!("{}: {}\r\n", name, value))
+                    .collect();
+
+                format!("{} {} {}\r\n{}\r\n{}", request.method, request.url, request.version, headers, request.body)
+            });
+
+        resource
+    }
+
+    /// Creates a scope for resources. Resources created through the scope are removed from the
+    /// server when the scope is dropped.
+    ///
+    /// Useful to reuse one server across subtests.
+    ///
+    /// ```
+    ///# extern crate http_test_server;
+    ///# use http_test_server::{TestServer, Resource};
+    /// let server = TestServer::new().unwrap();
+    ///
+    /// {
+    ///     let scope = server.scope();
+    ///     scope.create_resource("/user/settings");
+    /// }
+    ///
+    /// // "/user/settings" is not available anymore
+    /// ```
+    pub fn scope(&self) -> ResourceScope {
+        ResourceScope { resources: Arc::clone(&self.resources), created: Mutex::new(vec!()) }
+    }
+
+    /// Returns resource used to respond requests that don't match any other resource.
+    ///
+    /// By default it responds `404 Not Found` with an empty body. Configure it as any other
+    /// resource to customise the response. Calling this method again returns the same resource.
+    ///
+    /// ```
+    ///# extern crate http_test_server;
+    ///# use http_test_server::{TestServer, Resource};
+    /// let server = TestServer::new().unwrap();
+    ///
+    /// server.not_found_resource()
+    ///     .header("Content-Type", "application/json")
+    ///     .body(r#"{"error":"not found"}"#);
+    ///
+    /// // request: GET /unknown
+    ///
+    /// // HTTP/1.1 404 Not Found\r\n
+    /// // Content-Type: application/json\r\n
+    /// // Content-Length: 21\r\n
+    /// // \r\n
+    /// // {"error":"not found"}
+    /// ```
+    pub fn not_found_resource(&self) -> Resource {
+        let mut not_found_resource = self.config.not_found_resource.lock().unwrap();
+
+        not_found_resource.get_or_insert_with(|| {
+            Resource::new("").status(Status::NotFound).clone()
+        }).clone()
+    }
+
+    /// Creates a resource that responds with given status and a JSON error envelope as body.
+    ///
+    /// The body follows the format `{"error":{"status":<code>,"message":"<message>"}}` and
+    /// `Content-Type` is set to `application/json`.
+    ///
+    /// ```
+    ///# extern crate http_test_server;
+    ///# use http_test_server::{TestServer, Resource};
+    ///# use http_test_server::http::Status;
+    /// let server = TestServer::new().unwrap();
+    /// let resource = server.create_error_resource("/broken", Status::InternalServerError, "Something went wrong");
+    ///
+    /// // request: GET /broken
+    ///
+    /// // HTTP/1.1 500 Internal Server Error\r\n
+    /// // Connection: close\r\n
+    /// // Content-Type: application/json\r\n
+    /// // Content-Length: 57\r\n
+    /// // \r\n
+    /// // {"error":{"status":500,"message":"Something went wrong"}}
+    /// ```
+    pub fn create_error_resource(&self, uri: &str, status: Status, message: &str) -> Resource {
+        let resource = self.create_resource(uri);
+        let body = format!(
+            r#"{{"error":{{"status":{},"message":"{}"}}}}"#,
+            status as u16,
+            escape_json(message)
+        );
+
+        resource
+            .status(status)
+            .header("Content-Type", "application/json")
+            .body(body);
+
+        resource
+    }
+
+    /// Tells how a request would be handled, without making a request.
+    ///
+    /// Resolution follows the same logic used when serving requests, for a request with given
+    /// method and URL only. It has no headers and no body, so resources matching on them are not
+    /// selected. Request counts are not affected.
+    ///
+    /// ```
+    ///# extern crate http_test_server;
+    ///# use http_test_server::{TestServer, Resource, MatchOutcome};
+    ///# use http_test_server::http::Method;
+    /// let server = TestServer::new().unwrap();
+    /// server.create_resource("/get-only");
+    ///
+    /// assert_eq!(server.resolve_detailed("GET", "/get-only"), MatchOutcome::Matched(String::from("/get-only")));
+    /// assert_eq!(server.resolve_detailed("POST", "/get-only"), MatchOutcome::MethodNotAllowed(vec!(Method::GET)));
+    /// assert_eq!(server.resolve_detailed("GET", "/other"), MatchOutcome::NotFound);
+    /// ```
+    pub fn resolve_detailed(&self, method: &str, url: &str) -> MatchOutcome {
+        let resources = self.resources.lock().unwrap();
+        let request = bare_request(method, url);
+
+        match route(&request, &resources) {
+            Route::Matched(resource) => MatchOutcome::Matched(resource.get_uri()),
+            Route::MethodNotAllowed(methods) => MatchOutcome::MethodNotAllowed(methods),
+            Route::Options(methods) => MatchOutcome::Options(methods),
+            Route::NotFound => MatchOutcome::NotFound,
+            Route::NotImplemented => MatchOutcome::NotImplemented
+        }
+    }
+
+    /// Number of requests received by the resource that would handle given method and URL.
+    ///
+    /// Resource is selected with the same logic used when serving requests, for a request with
+    /// given method and URL only. It has no headers and no body, so resources matching on them,
+    /// e.g. with [`Resource::match_header`] or [`Resource::match_body`], are not selected. Returns `0`
+    /// when no resource matches.
+    /// ```
+    ///# extern crate http_test_server;
+    ///# use http_test_server::{TestServer, Resource};
+    /// let server = TestServer::new().unwrap();
+    /// server.create_resource("/user/{id}");
+    ///
+    /// assert_eq!(server.request_count_for("GET", "/user/1"), 0);
+    /// ```
+    /// [`Resource::match_header`]: struct.Resource.html#method.match_header
+    /// [`Resource::match_body`]: struct.Resource.html#method.match_body
+    pub fn request_count_for(&self, method: &str, url: &str) -> u32 {
+        let resources = self.resources.lock().unwrap();
+
+        match route(&bare_request(method, url), &resources) {
+            Route::Matched(resource) => resource.request_count(),
+            _ => 0
+        }
+    }
+
+    /// Number of requests received by the server, including requests not matching any resource.
+    ///
+    /// Requests to ignored paths are not counted.
+    /// ```
+    ///# extern crate http_test_server;
+    ///# use http_test_server::{TestServer, Resource};
+    /// let server = TestServer::new().unwrap();
+    ///
+    /// assert_eq!(server.total_request_count(), 0);
+    /// ```
+    pub fn total_request_count(&self) -> u32 {
+        self.config.total_request_count.load(Ordering::SeqCst)
+    }
+
+    /// Number of requests not matching any resource, answered with `404 Not Found` or by
+    /// [`not_found_resource`].
+    /// ```
+    ///# extern crate http_test_server;
+    ///# use http_test_server::{TestServer, Resource};
+    /// let server = TestServer::new().unwrap();
+    ///
+    /// assert_eq!(server.unmatched_request_count(), 0);
+    /// ```
+    /// [`not_found_resource`]: struct.TestServer.html#method.not_found_resource
+    pub fn unmatched_request_count(&self) -> u32 {
+        self.config.unmatched_request_count.load(Ordering::SeqCst)
+    }
+
+    /// Removes resource from server. Following requests are handled as if resource was never
+    /// created.
+    ///
+    /// ```
+    ///# extern crate http_test_server;
+    ///# use http_test_server::{TestServer, Resource};
+    /// let server = TestServer::new().unwrap();
+    /// let resource = server.create_resource("/user/{id}");
+    ///
+    /// server.remove_resource(&resource);
+    ///
+    /// assert!(server.unused_resources().is_empty());
+    /// ```
+    pub fn remove_resource(&self, resource: &Resource) {
+        self.resources.lock().unwrap().retain(|r| !r.is_same(resource));
+    }
+
+    /// Returns URIs of resources that didn't receive any request.
+    ///
+    /// Useful to ensure every resource was exercised at the end of a test.
+    /// ```
+    ///# extern crate http_test_server;
+    ///# use http_test_server::{TestServer, Resource};
+    /// let server = TestServer::new().unwrap();
+    /// server.create_resource("/user/{id}");
+    ///
+    /// assert_eq!(server.unused_resources(), vec!("/user/{id}"));
+    /// ```
+    pub fn unused_resources(&self) -> Vec<String> {
+        self.resources.lock().unwrap().iter()
+            .filter(|resource| resource.request_count() == 0)
+            .map(|resource| resource.get_uri())
+            .collect()
+    }
+
+    /// Retrieves information on new requests.
+    ///
+    /// Every receiver gets all requests received after it was created, so this method can be
+    /// called multiple times.
+    ///
+    /// ```no_run
+    ///# extern crate http_test_server;
+    ///# use http_test_server::{TestServer, Resource};
+    ///# use std::collections::HashMap;
+    /// let server = TestServer::new().unwrap();
+    ///
+    /// for request in server.requests().iter() {
+    ///     assert_eq!(request.url, "/endpoint");
+    ///     assert_eq!(request.method, "GET");
+    ///     assert_eq!(request.headers.get("Content-Type").unwrap(), "text");
+    ///     assert_eq!(request.body, "{\"name\":\"x\"}");
+    /// }
+    /// ```
+    pub fn requests(&self) -> mpsc::Receiver<Request> {
+        let (tx, rx) = mpsc::channel();
+
+        self.requests_tx.lock().unwrap().push(tx);
+
+        rx
+    }
+
+    /// Blocks until a request satisfying given predicate is received or timeout elapses.
+    ///
+    /// Requests already received by server resources are checked as well.
+    /// Returns `None` when timeout elapses.
+    ///
+    /// ```no_run
+    ///# extern crate http_test_server;
+    ///# use http_test_server::{TestServer, Resource};
+    ///# use http_test_server::http::Method;
+    /// use std::time::Duration;
+    ///
+    /// let server = TestServer::new().unwrap();
+    /// server.create_resource("/webhook").method(Method::POST);
+    ///
+    /// // trigger webhook call
+    ///
+    /// let request = server.wait_for_request(|request| request.url == "/webhook", Duration::from_secs(1));
+    ///
+    /// assert_eq!(request.unwrap().body, "{\"event\":\"done\"}");
+    /// ```
+    pub fn wait_for_request(&self, predicate: impl Fn(&Request) -> bool, timeout: Duration) -> Option<Request> {
+        let deadline = Instant::now() + timeout;
+        let (tx, rx) = mpsc::channel();
+
+        self.requests_tx.lock().unwrap().push(tx);
+
+        let received = self.resources.lock().unwrap().iter()
+            .flat_map(|resource| resource.requests_received())
+            .find(|request| predicate(request));
+
+        if received.is_some() {
+            return received;
+        }
+
+        loop {
+            let remaining = deadline.checked_duration_since(Instant::now())?;
+
+            match rx.recv_timeout(remaining) {
+                Ok(request) => if predicate(&request) {
+                    return Some(request);
+                },
+                Err(_) => return None
+            }
+        }
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        // after shutdown, port may already be in use by someone else
+        if let Some(listener_thread) = self.listener_thread.take() {
+            self.close();
+
+            // listener may be busy with a connection, so it's not waited for forever
+            let deadline = Instant::now() + Duration::from_secs(1);
+
+            while !listener_thread.is_finished() && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(5));
+            }
+        }
+    }
+}
+
+/// Resources registration guard. Removes resources it created when dropped.
+///
+/// Must be created through [`TestServer::scope`].
+///
+/// [`TestServer::scope`]: struct.TestServer.html#method.scope
+pub struct ResourceScope {
+    resources: ServerResources,
+    created: Mutex<Vec<Resource>>
+}
+
+impl ResourceScope {
+    /// Creates a new resource that lives as long as the scope.
+    ///
+    /// See [`TestServer::create_resource`].
+    ///
+    /// [`TestServer::create_resource`]: struct.TestServer.html#method.create_resource
+    pub fn create_resource(&self, uri: &str) -> Resource {
+        let resource = Resource::new(uri);
+
+        self.resources.lock().unwrap().push(resource.clone());
+        self.created.lock().unwrap().push(resource.clone());
+
+        resource
+    }
+}
+
+impl Drop for ResourceScope {
+    fn drop(&mut self) {
+        let created = self.created.lock().unwrap();
+        let mut resources = self.resources.lock().unwrap();
+
+        resources.retain(|resource| !created.iter().any(|c| c.is_same(resource)));
+    }
+}
+
+struct ActiveConnection<'a>(&'a AtomicUsize);
+
+impl<'a> Drop for ActiveConnection<'a> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn handle_connection(stream: TcpStream, resources: ServerResources, requests_tx: RequestsTX, config: SharedConfig) -> JoinHandle<()> {
+    config.active_connections.fetch_add(1, Ordering::SeqCst);
+
+    thread::spawn(move || {
+        let _active = ActiveConnection(&config.active_connections);
+
+        if config.tcp_nodelay.load(Ordering::Relaxed) && stream.set_nodelay(true).is_err() {
+            return;
+        }
+
+        let mut write_stream = match stream.try_clone() {
+            Ok(write_stream) => write_stream,
+            Err(_) => return
+        };
+        let mut reader = BufReader::new(stream);
+        let keep_alive_max = *config.keep_alive_max.lock().unwrap();
+        let keep_alive_timeout = *config.keep_alive_timeout.lock().unwrap();
+        let read_timeout = *config.read_timeout.lock().unwrap();
+        let mut served = 0;
+
+        if !wait_for_request(&mut reader, keep_alive_timeout.or(read_timeout), read_timeout) {
+            return;
+        }
+
+        loop {
+            let (mut request, over_limit) = match read_request(&mut reader, &resources) {
+                Ok(read) => read,
+                Err(ref error) if is_timeout(error) || is_closed(error) => break,
+                Err(_) => {
+                    let _ = write_stream.write_all(b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\nContent-Length: 0\r\n\r\n");
+                    break;
+                }
+            };
+            served += 1;
+
+            // body over the limit was not read, connection can't be reused
+            let is_last = match keep_alive_max {
+                Some(max) => served >= max || wants_close(&request) || over_limit.is_some(),
+                None => true
+            };
+
+            if let Some(status) = ignored_path_status(&config, &request.url) {
+                let response = Resource::new_literal(&request.url).status(status).build_response(&request);
+                if write_response(&mut write_stream, response, is_last, None).is_err() {
+                    break;
+                }
+            } else {
+                config.total_request_count.fetch_add(1, Ordering::SeqCst);
+                let resource = match over_limit {
+                    Some(ref resource) => {
+                        accept_request(&mut request, resource);
+                        Resource::new_literal(&request.url).status(Status::PayloadTooLarge).clone()
+                    },
+                    None => find_resource(&mut request, resources.clone(), &config)
+                };
+
+                #[cfg(feature = "serde")]
+                record_request(&config, &request);
+
+                let delay = resource.get_delay(|| splitmix64(&mut config.delay_random.lock().unwrap()))
+                    .or_else(|| resource.get_latency(config.active_connections.load(Ordering::SeqCst)))
+                    .or(*config.default_delay.lock().unwrap());
+
+                if let Some(delay) = delay {
+                    thread::sleep(delay);
+                }
+
+                // lets clients know connection won't be reused. Streams are closed by the server.
+                let close_header = is_last && !resource.is_stream() && !resource.has_header("Connection");
+
+                let close_after_bytes = resource.get_close_after_bytes();
+
+                // stream clients subscribe before the response is built, so no data is missed
+                let subscription = if resource.is_stream() && request.method_enum != Method::HEAD && close_after_bytes.is_none() {
+                    Some(resource.subscribe(&request))
+                } else {
+                    None
+                };
+
+                let response = resource.build_response(&request);
+
+                let written = match close_after_bytes {
+                    Some(bytes) => {
+                        let truncated = &response[..bytes.min(response.len())];
+                        write_throttled(&mut write_stream, truncated, resource.get_throttle())
+                    },
+                    None => write_response(&mut write_stream, response, close_header, resource.get_throttle())
+                };
+
+                requests_tx.lock().unwrap()
+                    .retain(|tx| tx.send(request.clone()).is_ok());
+
+                if close_after_bytes.is_some() {
+                    let _ = write_stream.shutdown(Shutdown::Both);
+                    break;
+                }
+
+                // client went away before response was written
+                if written.is_err() {
+                    break;
+                }
+
+                if let Some((replay, receiver)) = subscription {
+                    let client_resource = resource.clone();
+
+                    thread::spawn(move || {
+                        for line in reader.lines() {
+                            match line {
+                                Ok(line) => client_resource.notify_client_message(&line),
+                                Err(_) => break
+                            }
+                        }
+                    });
+
+                    stream_data(&resource, &mut write_stream, &replay, receiver);
+                    let _ = write_stream.shutdown(Shutdown::Both);
+                    break;
+                }
+            }
+
+            if is_last {
+                let _ = write_stream.shutdown(Shutdown::Write);
+                break;
+            }
+
+            if !wait_for_request(&mut reader, keep_alive_timeout, read_timeout) {
+                break;
+            }
+        }
+    })
+}
+
+// reads request. When its body is larger than the limit of the resource answering it, body is
+// not read and the resource is returned along with the request.
+fn read_request(reader: &mut BufReader<TcpStream>, resources: &ServerResources) -> Result<(Request, Option<Resource>), Error> {
+    let (method, url, version) = parse_request_header(reader)?;
+    let headers = parse_headers(reader)?;
+    let method_enum = Method::from(method.as_str());
+    let mut request = Request {
+        url, method, method_enum, version, headers, body: String::new(), matched_uri: None, request_id: None
+    };
+
+    let over_limit = over_limit_resource(&request, resources);
+
+    if over_limit.is_none() {
+        request.body = read_body(reader, &request.headers)?;
+    }
+
+    Ok((request, over_limit))
+}
+
+// resource is selected before the body is read, so body matchers are not considered
+fn over_limit_resource(request: &Request, resources: &ServerResources) -> Option<Resource> {
+    let content_length = content_length(&request.headers);
+    let resources = resources.lock().unwrap();
+
+    resources.iter()
+        .filter(|r| r.matches_uri(&request.url) && r.matches_method(&request.method) && r.matches_headers(request))
+        .min_by_key(|r| r.wildcard_count())
+        .filter(|r| r.get_body_limit().is_some_and(|limit| content_length > limit))
+        .cloned()
+}
+
+fn invalid_request(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+fn wants_close(request: &Request) -> bool {
+    request.headers.iter()
+        .any(|(name, value)| name.eq_ignore_ascii_case("Connection") && value.eq_ignore_ascii_case("close"))
+}
+
+fn write_response(stream: &mut TcpStream, response: Vec<u8>, close: bool, throttle: Option<usize>) -> Result<(), Error> {
+    let response = if close {
+        // status line is followed by headers, add Connection header right after it
+        let status_line_end = response.windows(2).position(|w| w == b"\r\n").unwrap() + 2;
+        let mut with_close = response[..status_line_end].to_vec();
+        with_close.extend_from_slice(b"Connection: close\r\n");
+        with_close.extend_from_slice(&response[status_line_end..]);
+        with_close
+    } else {
+        response
+    };
+
+    write_throttled(stream, &response, throttle)?;
+    stream.flush()
+}
+
+fn write_throttled(stream: &mut TcpStream, data: &[u8], bytes_per_sec: Option<usize>) -> Result<(), Error> {
+    let bytes_per_sec = match bytes_per_sec {
+        Some(bytes_per_sec) => bytes_per_sec,
+        None => return stream.write_all(data)
+    };
+
+    // small writes ten times a second approximate the rate
+    let chunk_size = (bytes_per_sec / 10).max(1);
+
+    for chunk in data.chunks(chunk_size) {
+        stream.write_all(chunk)?;
+        stream.flush()?;
+        thread::sleep(Duration::from_secs_f64(chunk.len() as f64 / bytes_per_sec as f64));
+    }
+
+    Ok(())
+}
+
+fn wait_for_request(reader: &mut BufReader<TcpStream>, idle_timeout: Option<Duration>, read_timeout: Option<Duration>) -> bool {
+    if reader.get_ref().set_read_timeout(idle_timeout).is_err() {
+        return false;
+    }
+
+    let has_data = match reader.fill_buf() {
+        Ok(buffer) => !buffer.is_empty(),
+        Err(_) => false
+    };
+
+    has_data && reader.get_ref().set_read_timeout(read_timeout).is_ok()
+}
+
+fn is_timeout(error: &Error) -> bool {
+    // platforms differ on the error kind reported when a read times out
+    error.kind() == ErrorKind::WouldBlock || error.kind() == ErrorKind::TimedOut
+}
+
+fn is_closed(error: &Error) -> bool {
+    error.kind() == ErrorKind::UnexpectedEof
+}
+
+fn stream_data(resource: &Resource, stream: &mut TcpStream, replay: &[u8], receiver: mpsc::Receiver<Vec<u8>>) {
+    let write_timeout = match resource.get_slow_client_policy() {
+        SlowClientPolicy::Block => None,
+        SlowClientPolicy::Drop(timeout) => Some(timeout)
+    };
+
+    if stream.set_write_timeout(write_timeout).is_err() {
+        return;
+    }
+
+    let chunked = resource.is_chunked();
+    let throttle = resource.get_throttle();
+
+    if !replay.is_empty() && write_stream_data(stream, replay, chunked, throttle).and_then(|_| stream.flush()).is_err() {
+        return;
+    }
+
+    for (index, mut data) in receiver.iter().enumerate() {
+        if let (true, Some(delay)) = (index > 0, resource.get_stream_chunk_delay()) {
+            thread::sleep(delay);
+        }
+
+        let written = match resource.get_stream_chunk_size() {
+            Some(chunk_size) => {
+                while let Ok(more) = receiver.try_recv() {
+                    data.extend_from_slice(&more);
+                }
+
+                data.chunks(chunk_size).try_for_each(|chunk| write_stream_data(stream, chunk, chunked, throttle))
+            },
+            None => write_stream_data(stream, &data, chunked, throttle)
+        };
+
+        // client went away or, with SlowClientPolicy::Drop, didn't read data in time
+        if written.and_then(|_| stream.flush()).is_err() {
+            return;
+        }
+    }
+
+    // connections closed through resource, chunked body can be terminated
+    if chunked {
+        let _ = stream.write_all(b"0\r\n\r\n");
+    }
+}
+
+fn write_stream_data(stream: &mut TcpStream, data: &[u8], chunked: bool, throttle: Option<usize>) -> Result<(), Error> {
+    if chunked {
+        write_throttled(stream, &resource::encode_chunk(data), throttle)
+    } else {
+        write_throttled(stream, data, throttle)
+    }
+}
+
+#[cfg(feature = "serde")]
+fn record_request(config: &ServerConfig, request: &Request) {
+    if let Some(ref dir) = *config.record_dir.lock().unwrap() {
+        let sequence = config.record_count.fetch_add(1, Ordering::SeqCst) + 1;
+        let file = dir.join(format!("request-{:04}.json", sequence));
+
+        // recording is best effort, failing to write a file must not prevent the response
+        if let Ok(json) = serde_json::to_string_pretty(request) {
+            let _ = fs::create_dir_all(dir).and_then(|_| fs::write(file, json));
+        }
+    }
+}
+
+fn escape_json(value: &str) -> String {
+    let mut escaped = String::new();
+
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c)
+        }
+    }
+
+    escaped
+}
+
+fn parse_header(message: String) -> Result<(String, String), Error> {
+    let parts: Vec<&str> = message.splitn(2, ':').collect();
+
+    if parts.len() < 2 {
+        return Err(invalid_request("Malformed header"));
+    }
+
+    Ok((String::from(parts[0]), String::from(parts[1].trim())))
+}
+
+fn parse_headers(reader: &mut dyn BufRead) -> Result<HashMap<String, String>, Error> {
+    let mut headers = HashMap::new();
+
+    for line in reader.lines() {
+        let line = line?;
+
+        if line.is_empty() {
+            break
+        }
+
+        let (name, value) = parse_header(line)?;
+        headers.insert(name, value);
+    }
+
+    Ok(headers)
+}
+
+fn content_length(headers: &HashMap<String, String>) -> usize {
+    headers.iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("Content-Length"))
+        .and_then(|(_, value)| value.parse::<usize>().ok())
+        .unwrap_or(0)
+}
+
+fn read_body(reader: &mut dyn BufRead, headers: &HashMap<String, String>) -> Result<String, Error> {
+    let content_length = content_length(headers);
+
+    // reads what client sends up to Content-Length, so a large Content-Length alone doesn't allocate
+    let mut body = Vec::new();
+    reader.take(content_length as u64).read_to_end(&mut body)?;
+
+    if body.len() < content_length {
+        return Err(invalid_request("Incomplete body"));
+    }
+
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
+fn parse_request_header(reader: &mut dyn BufRead) -> Result<(String, String, String), Error> {
+    let mut request_header = String::from("");
+
+    if reader.read_line(&mut request_header)? == 0 {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "Connection closed"));
+    }
+
+    let request_header: Vec<&str> = request_header
+        .split_whitespace().collect();
+
+    if request_header.len() < 2 {
+        return Err(invalid_request("Malformed request line"));
+    }
+
+    let version = request_header.get(2).map(|version| version.to_string()).unwrap_or_default();
+
+    Ok((request_header[0].to_string(), request_header[1].to_string(), version))
+}
+
+fn bare_request(method: &str, url: &str) -> Request {
+    Request {
+        url: String::from(url),
+        method: String::from(method),
+        method_enum: Method::from(method),
+        version: String::from("HTTP/1.1"),
+        headers: HashMap::new(),
+        body: String::new(),
+        matched_uri: None,
+        request_id: None
+    }
+}
+
+fn ignored_path_status(config: &SharedConfig, url: &str) -> Option<Status> {
+    let path = url.split('?').next().unwrap_or("");
+
+    config.ignored_paths.lock().unwrap().iter()
+        .find(|(pattern, _)| pattern.is_match(path))
+        .map(|(_, status)| *status)
+}
+
+fn accept_request(request: &mut Request, resource: &Resource) {
+    request.matched_uri = Some(resource.get_uri());
+    resource.assign_request_id(request);
+    resource.add_request(request.clone());
+    resource.increment_request_count();
+}
+
+fn find_resource(request: &mut Request, resources: ServerResources, config: &ServerConfig) -> Resource {
+    let resources = resources.lock().unwrap();
+
+    match route(request, &resources) {
+        Route::Matched(resource) => {
+            accept_request(request, resource);
+            resource.clone()
+        },
+        Route::MethodNotAllowed(methods) => {
+            let resource = Resource::new_literal(&request.url);
+            resource.status(Status::MethodNotAllowed).header("Allow", &allow_header(&methods));
+
+            if let Some(ref body) = *config.method_not_allowed_body.lock().unwrap() {
+                resource.body(body.as_str());
+            }
+
+            resource
+        },
+        Route::Options(methods) => {
+            Resource::new_literal(&request.url)
+                .status(Status::NoContent)
+                .header("Allow", &allow_header(&methods))
+                .clone()
+        },
+        Route::NotImplemented => {
+            Resource::new_literal(&request.url).status(Status::NotImplemented).clone()
+        },
+        Route::NotFound => {
+            config.unmatched_request_count.fetch_add(1, Ordering::SeqCst);
+
+            match *config.not_found_resource.lock().unwrap() {
+                Some(ref resource) => {
+                    resource.assign_request_id(request);
+                    resource.add_request(request.clone());
+                    resource.increment_request_count();
+                    resource.clone()
+                },
+                None => Resource::new_literal(&request.url).status(Status::NotFound).clone()
+            }
+        }
+    }
+}
+
+fn is_unknown_method(request: &Request) -> bool {
+    matches!(request.method_enum, Method::Other(_))
+}
+
+fn allow_header(methods: &[Method]) -> String {
+    let allow: Vec<&str> = methods.iter().map(|method| method.value()).collect();
+
+    allow.join(", ")
+}
+
+enum Route<'a> {
+    Matched(&'a Resource),
+    MethodNotAllowed(Vec<Method>),
+    Options(Vec<Method>),
+    NotFound,
+    NotImplemented
+}
+
+fn route<'a>(request: &Request, resources: &'a [Resource]) -> Route<'a> {
+    let url = &request.url;
+    let matches = |r: &&Resource| r.matches_uri(url) && r.matches_method(&request.method) && r.matches_request(request);
+
+    // literal URIs win over patterns, first defined resource wins among equally specific ones
+    if let Some(resource) = resources.iter().filter(matches).min_by_key(|r| r.wildcard_count()) {
+        return Route::Matched(resource);
+    }
+
+    // HEAD requests are answered by GET resources when there is no HEAD resource.
+    let is_head = request.method_enum == Method::HEAD;
+    let matches_get = |r: &&Resource| r.matches_uri(url) && r.matches_method("GET") && r.matches_request(request);
+
+    if is_head {
+        if let Some(resource) = resources.iter().filter(matches_get).min_by_key(|r| r.wildcard_count()) {
+            return Route::Matched(resource);
+        }
+    }
+
+    // methods not covered by `Method` are only answered by resources handling them explicitly
+    if is_unknown_method(request) {
+        return Route::NotImplemented;
+    }
+
+    // resource not found, check whether to show 404, MethodNotAllowed or answer OPTIONS.
+    let resources_for_uri: Vec<&Resource> = resources.iter().filter(|r| r.matches_uri(url)).collect();
+    let matches_method = |r: &&Resource| r.matches_method(&request.method) || (is_head && r.matches_method("GET"));
+
+    if resources_for_uri.is_empty() || resources_for_uri.iter().any(matches_method) {
+        return Route::NotFound;
+    }
+
+    let is_options = request.method_enum == Method::OPTIONS;
+
+    let mut allowed_methods = vec!();
+
+    for resource in resources_for_uri {
+        for method in resource.get_methods() {
+            if !allowed_methods.contains(&method) {
+                allowed_methods.push(method);
+            }
+        }
+    }
+
+    if is_options {
+        return Route::Options(allowed_methods);
+    }
+
+    Route::MethodNotAllowed(allowed_methods)
+}
+
+/// Outcome of matching a request against the server's resources.
+///
+/// See [`TestServer::resolve_detailed`].
+///
+/// [`TestServer::resolve_detailed`]: struct.TestServer.html#method.resolve_detailed
+#[derive(Debug, PartialEq)]
+#[non_exhaustive]
+pub enum MatchOutcome {
+    /// Request matches resource with given URI.
+    Matched(String),
+    /// There are resources for the URL, but none for the method. Contains methods configured for the URL.
+    MethodNotAllowed(Vec<Method>),
+    /// `OPTIONS` request answered automatically. Contains methods configured for the URL.
+    Options(Vec<Method>),
+    /// No resource matches the URL.
+    NotFound,
+    /// Method is unknown and no resource handles it.
+    NotImplemented
+}
+
+
+/// Request information
+///
+/// this contains basic information about a request received.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[non_exhaustive]
+pub struct Request {
+    /// Request URL
+    pub url: String,
+    /// HTTP method
+    pub method: String,
+    /// HTTP method as [`Method`]. Unknown methods are represented by `Method::Other`.
+    ///
+    /// [`Method`]: http/enum.Method.html
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub method_enum: Method,
+    /// HTTP version from request line, e.g. `HTTP/1.1`. Empty when request line has none.
+    pub version: String,
+    /// Request headers
+    pub headers: HashMap<String, String>,
+    /// Request body. Empty when request has no `Content-Length`.
+    pub body: String,
+    /// URI of the resource that handled the request, as defined when resource was created.
+    /// `None` when no resource matched, e.g. `404` and `405` responses.
+    pub matched_uri: Option<String>,
+    /// Id sent in the header added by [`Resource::with_request_id_header`]. `None` when resource
+    /// doesn't add it.
+    ///
+    /// [`Resource::with_request_id_header`]: struct.Resource.html#method.with_request_id_header
+    pub request_id: Option<String>
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+    use std::io::BufReader;
+    use std::io::ErrorKind;
+    use std::net::TcpStream;
+    use std::time::{Duration, Instant};
+    use std::sync::mpsc;
+    use super::*;
+
+    fn make_request(port: u16, uri: &str) -> TcpStream {
+       request(port, uri, "GET")
+    }
+
+    fn make_post_request(port: u16, uri: &str) -> TcpStream {
+       request(port, uri, "POST")
+    }
+
+    fn request(port: u16, uri: &str, method: &str) -> TcpStream {
+        let host = format!("127.0.0.1:{}", port);
+        let mut stream = TcpStream::connect(host).unwrap();
+        let request = format!(
+            "{} {} HTTP/1.1\r\nContent-Type: text\r\n\r\n",
+            method,
+            uri
+        );
+
+        stream.write_all(request.as_bytes()).unwrap();
+        stream.flush().unwrap();
+
+        stream
+    }
+
+    #[test]
+    fn returns_404_when_requested_enexistent_resource() {
+        let server = TestServer::new().unwrap();
+        let stream = make_request(server.port(), "/something");
+
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+
+        assert_eq!(line, "HTTP/1.1 404 Not Found\r\n");
+    }
+
+    #[test]
+    fn should_expose_bound_address() {
+        let server = TestServer::new().unwrap();
+
+        assert_eq!(server.address().port(), server.port());
+        assert!(server.address().ip().is_loopback());
+    }
+
+    #[test]
+    fn should_bind_to_given_address() {
+        let server = TestServer::new_with_addr("127.0.0.1:0").unwrap();
+        server.create_resource("/here");
+
+        assert_eq!(server.address().ip().to_string(), "127.0.0.1");
+
+        let mut response = String::new();
+        make_request(server.port(), "/here").read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 Ok\r\n"));
+    }
+
+    #[test]
+    fn should_close_server_bound_to_all_interfaces() {
+        let server = TestServer::new_with_addr("0.0.0.0:0").unwrap();
+        let port = server.port();
+
+        server.close();
+        thread::sleep(Duration::from_millis(200));
+
+        let stream = TcpStream::connect(format!("127.0.0.1:{}", port));
+
+        assert_eq!(stream.unwrap_err().kind(), ErrorKind::ConnectionRefused);
+    }
+
+    #[test]
+    fn server_should_use_random_port() {
+        let server = TestServer::new().unwrap();
+        let server_2 = TestServer::new().unwrap();
+
+        assert_ne!(server.port(), server_2.port());
+    }
+
+    #[test]
+    fn should_return_error_when_port_is_unavailable() {
+        let server = TestServer::new().unwrap();
+
+        assert!(TestServer::new_with_port(server.port()).is_err());
+    }
+
+    #[test]
+    fn should_bind_to_ipv4_loopback() {
+        let server = TestServer::new_ipv4().unwrap();
+
+        assert!(server.socket_addr().is_ipv4());
+        assert!(server.socket_addr().ip().is_loopback());
+    }
+
+    #[test]
+    fn should_bind_to_ipv6_loopback() {
+        let server = TestServer::new_ipv6().unwrap();
+        server.create_resource("/something");
+
+        assert!(server.socket_addr().is_ipv6());
+
+        let mut stream = TcpStream::connect(server.socket_addr()).unwrap();
+        stream.write_all(b"GET /something HTTP/1.1\r\n\r\n").unwrap();
+
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+
+        assert_eq!(line, "HTTP/1.1 200 Ok\r\n");
+    }
+
+    #[test]
+    fn should_close_ipv6_listener() {
+        let server = TestServer::new_ipv6().unwrap();
+        let address = server.socket_addr();
+        server.close();
+
+        thread::sleep(Duration::from_millis(200));
+
+        assert!(TcpStream::connect(address).is_err());
+    }
+
+    #[test]
+    fn should_close_connection() {
+        let server = TestServer::new().unwrap();
+        server.close();
+
+        thread::sleep(Duration::from_millis(200));
+
+        let host = format!("127.0.0.1:{}", server.port());
+        let stream = TcpStream::connect(host);
+
+        assert!(stream.is_err());
+        if let Err(e) = stream {
+            assert_eq!(e.kind(), ErrorKind::ConnectionRefused);
+        }
+    }
+
+    #[test]
+    fn should_handle_multiple_resources() {
+        let server = TestServer::new().unwrap();
+        let resource = server.create_resource("/this");
+        resource.status(Status::OK).body("<this body>");
+        thread::sleep(Duration::from_millis(200));
+        let resource2 = server.create_resource("/that");
+        resource2.status(Status::OK).body("<that body>");
+
+        assert_eq!(resource.request_count(), 0);
+        assert_eq!(resource2.request_count(), 0);
+
+        let _ = make_request(server.port(), "/this");
+
+        thread::sleep(Duration::from_millis(200));
+        let _ = make_request(server.port(), "/that");
+        thread::sleep(Duration::from_millis(200));
+
+        assert_eq!(resource.request_count(), 1);
+        assert_eq!(resource2.request_count(), 1);
+    }
+
+    #[test]
+    fn should_create_resource() {
+        let server = TestServer::new().unwrap();
+        server.create_resource("/something");
+
+        let stream = make_request(server.port(), "/something");
+
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+
+        assert_eq!(line, "HTTP/1.1 200 Ok\r\n");
+    }
+
+    #[test]
+    fn should_return_configured_status_for_resource_resource() {
+        let server = TestServer::new().unwrap();
+        let resource = server.create_resource("/something-else");
+
+        resource.status(Status::OK);
+
+        let stream = make_request(server.port(), "/something-else");
+
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+
+        assert_eq!(line, "HTTP/1.1 200 Ok\r\n");
+    }
+
+    #[test]
+    fn should_return_resource_body() {
+        let server = TestServer::new().unwrap();
+        let resource = server.create_resource("/something-else");
+
+        resource.status(Status::OK).body("<some body>");
+
+        let stream = make_request(server.port(), "/something-else");
+
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        reader.read_to_string(&mut line).unwrap();
+
+        assert_eq!(line, "HTTP/1.1 200 Ok\r\nConnection: close\r\nContent-Length: 11\r\n\r\n<some body>");
+    }
+
+    #[test]
+    fn should_return_resource_body_with_params() {
+        let server = TestServer::new().unwrap();
+        let resource = server.create_resource("/user/{userId}/{thing_id}/{yepyep}");
+
+        resource.status(Status::OK).body("User: {path.userId} Thing: {path.thing_id} Sth: {path.yepyep}");
+
+        let stream = make_request(server.port(), "/user/123/abc/Hello!");
+
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        reader.read_to_string(&mut line).unwrap();
+
+        assert_eq!(line, "HTTP/1.1 200 Ok\r\nConnection: close\r\nContent-Length: 32\r\n\r\nUser: 123 Thing: abc Sth: Hello!");
+    }
+
+    #[test]
+    fn should_work_with_regex_uri() {
+        let server = TestServer::new().unwrap();
+        let resource = server.create_resource("/hello/[0-9]/[A-z]/.*");
+
+        resource.method(Method::POST).status(Status::OK).body("<some body>");
+
+        let stream = make_post_request(server.port(), "/hello/8/b/doesntmatter-hehe");
+
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        reader.read_to_string(&mut line).unwrap();
+
+        assert_eq!(line, "HTTP/1.1 200 Ok\r\nConnection: close\r\nContent-Length: 11\r\n\r\n<some body>");
+    }
+
+
+    #[test]
+    fn should_listen_to_defined_method() {
+        let server = TestServer::new().unwrap();
+        let resource = server.create_resource("/something-else");
+
+        resource.method(Method::POST).status(Status::OK).body("<some body>");
+
+        let stream = make_post_request(server.port(), "/something-else");
+
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        reader.read_to_string(&mut line).unwrap();
+
+        assert_eq!(line, "HTTP/1.1 200 Ok\r\nConnection: close\r\nContent-Length: 11\r\n\r\n<some body>");
+    }
+
+    #[test]
+    fn should_allow_multiple_methods_for_same_uri() {
+        let server = TestServer::new().unwrap();
+        let resource = server.create_resource("/something-else");
+        let resource2 = server.create_resource("/something-else");
+
+        resource.method(Method::GET).status(Status::OK).body("<some body GET>");
+        resource2.method(Method::POST).status(Status::OK).body("<some body POST>");
+
+        let stream_get = make_request(server.port(), "/something-else");
+        let stream_post = make_post_request(server.port(), "/something-else");
+
+        let mut reader = BufReader::new(stream_get);
+        let mut line = String::new();
+        reader.read_to_string(&mut line).unwrap();
+
+        let mut reader = BufReader::new(stream_post);
+        let mut line2 = String::new();
+        reader.read_to_string(&mut line2).unwrap();
+
+        assert_eq!(line, "HTTP/1.1 200 Ok\r\nConnection: close\r\nContent-Length: 15\r\n\r\n<some body GET>");
+        assert_eq!(line2, "HTTP/1.1 200 Ok\r\nConnection: close\r\nContent-Length: 16\r\n\r\n<some body POST>");
+    }
+
+    #[test]
+    fn should_respond_to_any_method() {
+        let server = TestServer::new().unwrap();
+        let resource = server.create_resource("/anything");
+
+        resource.any_method().body("<any>");
+
+        for method in ["GET", "POST", "DELETE"].iter() {
+            let stream = request(server.port(), "/anything", method);
+
+            let mut reader = BufReader::new(stream);
+            let mut line = String::new();
+            reader.read_to_string(&mut line).unwrap();
+
+            assert_eq!(line, "HTTP/1.1 200 Ok\r\nConnection: close\r\nContent-Length: 5\r\n\r\n<any>");
+        }
+
+        assert_eq!(resource.request_count(), 3);
+    }
+
+    #[test]
+    fn should_respond_not_implemented_to_unknown_methods() {
+        let server = TestServer::new().unwrap();
+        server.create_resource("/x");
+        server.create_resource("/any").any_method();
+        server.create_resource("/foobar").method(Method::Other(String::from("FOOBAR")));
+
+        let mut response = String::new();
+        request(server.port(), "/x", "FOOBAR").read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 501 Not Implemented\r\n"));
+
+        for uri in &["/any", "/foobar"] {
+            let mut response = String::new();
+            request(server.port(), uri, "FOOBAR").read_to_string(&mut response).unwrap();
+            assert!(response.starts_with("HTTP/1.1 200 Ok\r\n"), "{}", uri);
+        }
+
+        assert_eq!(server.resolve_detailed("PROPFIND", "/x"), MatchOutcome::NotImplemented);
+    }
+
+    #[test]
+    fn should_select_resource_matching_header_regex() {
+        let server = TestServer::new().unwrap();
+        server.create_resource("/secret").match_header_regex("Authorization", "Bearer .+").body("<secret>");
+        server.create_resource("/secret").status(Status::Unauthorized);
+
+        let host = format!("127.0.0.1:{}", server.port());
+
+        let mut stream = TcpStream::connect(&host).unwrap();
+        stream.write_all(b"GET /secret HTTP/1.1\r\nAuthorization: Bearer abc.123\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert_eq!(response, "HTTP/1.1 200 Ok\r\nConnection: close\r\nContent-Length: 8\r\n\r\n<secret>");
+
+        let mut stream = TcpStream::connect(&host).unwrap();
+        stream.write_all(b"GET /secret HTTP/1.1\r\nAuthorization: Basic abc\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert_eq!(response, "HTTP/1.1 401 Unauthorized\r\nConnection: close\r\nContent-Length: 0\r\n\r\n");
+    }
+
+    #[test]
+    fn should_return_404_when_header_does_not_match() {
+        let server = TestServer::new().unwrap();
+        server.create_resource("/secret").match_header_regex("Authorization", "Bearer .+");
+
+        let stream = make_request(server.port(), "/secret");
+
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+
+        assert_eq!(line, "HTTP/1.1 404 Not Found\r\n");
+    }
+
+    #[test]
+    fn should_close_connection_after_response() {
+        let server = TestServer::new().unwrap();
+        server.create_resource("/something-else").body("<some body>");
+
+        let mut stream = TcpStream::connect(server.address()).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        stream.write_all(b"GET /something-else HTTP/1.1\r\nConnection: keep-alive\r\n\r\n").unwrap();
+
+        // fails with timeout if server keeps the connection open
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert_eq!(response, "HTTP/1.1 200 Ok\r\nConnection: close\r\nContent-Length: 11\r\n\r\n<some body>");
+    }
+
+    #[test]
+    fn should_not_add_connection_header_when_resource_defines_it() {
+        let server = TestServer::new().unwrap();
+        server.create_resource("/something-else").header("Connection", "keep-alive");
+
+        let mut response = String::new();
+        make_request(server.port(), "/something-else").read_to_string(&mut response).unwrap();
+
+        assert_eq!(response, "HTTP/1.1 200 Ok\r\nConnection: keep-alive\r\nContent-Length: 0\r\n\r\n");
+    }
+
+    #[test]
+    fn should_return_405_when_method_not_defined() {
+        let server = TestServer::new().unwrap();
+        let resource = server.create_resource("/something-else");
+
+        resource.method(Method::POST).status(Status::OK).body("<some body>");
+
+        let stream = make_request(server.port(), "/something-else");
+
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        reader.read_to_string(&mut line).unwrap();
+
+        assert_eq!(line, "HTTP/1.1 405 Method Not Allowed\r\nConnection: close\r\nAllow: POST\r\nContent-Length: 0\r\n\r\n");
+    }
+
+    #[test]
+    fn should_respond_405_with_custom_body() {
+        let server = TestServer::new().unwrap();
+        server.create_resource("/something-else").method(Method::POST);
+        server.create_resource("/something-else").method(Method::PUT);
+        server.method_not_allowed_body("not here");
+
+        let mut response = String::new();
+        make_request(server.port(), "/something-else").read_to_string(&mut response).unwrap();
+
+        assert_eq!(response, "HTTP/1.1 405 Method Not Allowed\r\nConnection: close\r\nAllow: POST, PUT\r\nContent-Length: 8\r\n\r\nnot here");
+    }
+
+    #[test]
+    fn should_resolve_requests_without_network() {
+        let server = TestServer::new().unwrap();
+        let resource = server.create_resource("/user/{id}");
+        server.create_resource("/user/{id}").method(Method::PUT);
+        server.create_resource("/user/{id}").method(Method::PUT);
+
+        assert_eq!(server.resolve_detailed("GET", "/user/1"), MatchOutcome::Matched(String::from("/user/{id}")));
+        assert_eq!(server.resolve_detailed("POST", "/user/1"), MatchOutcome::MethodNotAllowed(vec!(Method::GET, Method::PUT)));
+        assert_eq!(server.resolve_detailed("GET", "/account"), MatchOutcome::NotFound);
+        assert_eq!(resource.request_count(), 0);
+    }
+
+    #[test]
+    fn should_include_matched_uri_in_requests() {
+        let server = TestServer::new().unwrap();
+        server.create_resource("/user/{id}");
+        let requests = server.requests();
+
+        let _ = make_request(server.port(), "/user/123");
+        let _ = make_request(server.port(), "/account/123");
+
+        let mut received: Vec<Request> = (0..2).map(|_| requests.recv_timeout(Duration::from_secs(2)).unwrap()).collect();
+        received.sort_by(|a, b| b.url.cmp(&a.url));
+
+        assert_eq!(received[0].matched_uri, Some(String::from("/user/{id}")));
+        assert_eq!(received[1].matched_uri, None);
+    }
+
+    #[test]
+    fn should_prefer_literal_uri_over_path_parameters() {
+        let server = TestServer::new().unwrap();
+        server.create_resource("/users/{id}").body("user {path.id}");
+        server.create_resource("/users/me").body("me");
+
+        let mut response = String::new();
+        make_request(server.port(), "/users/me").read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("\r\n\r\nme"));
+
+        let mut response = String::new();
+        make_request(server.port(), "/users/123").read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("\r\n\r\nuser 123"));
+    }
+
+    #[test]
+    fn should_prefer_literal_uri_regardless_of_creation_order() {
+        let server = TestServer::new().unwrap();
+        server.create_resource("/users/me").body("me");
+        server.create_resource("/users/{id}").body("user {path.id}");
+        server.create_resource("/users/.*").body("regex");
+
+        let mut response = String::new();
+        make_request(server.port(), "/users/me").read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("\r\n\r\nme"));
+
+        let mut response = String::new();
+        make_request(server.port(), "/users/123").read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("\r\n\r\nuser 123"));
+    }
+
+    #[test]
+    fn should_prefer_uri_with_file_extension_over_path_parameters() {
+        let server = TestServer::new().unwrap();
+        server.create_resource("/reports/{name}").body("report {path.name}");
+        server.create_resource("/reports/summary.pdf").body("summary");
+
+        let mut response = String::new();
+        make_request(server.port(), "/reports/summary.pdf").read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("\r\n\r\nsummary"));
+    }
+
+    #[test]
+    fn should_respond_unmatched_requests_with_not_found_resource() {
+        let server = TestServer::new().unwrap();
+        server.create_resource("/something");
+        server.not_found_resource()
+            .header("Content-Type", "application/json")
+            .body(r#"{"error":"not found"}"#);
+
+        let mut response = String::new();
+        make_request(server.port(), "/unknown").read_to_string(&mut response).unwrap();
+
+        assert_eq!(
+            response,
+            "HTTP/1.1 404 Not Found\r\nConnection: close\r\nContent-Type: application/json\r\nContent-Length: 21\r\n\r\n{\"error\":\"not found\"}"
+        );
+        assert_eq!(server.not_found_resource().request_count(), 1);
+    }
+
+    #[test]
+    fn should_send_requests_to_every_receiver() {
+        let server = TestServer::new().unwrap();
+        server.create_resource("/something");
+        let first = server.requests();
+        let second = server.requests();
+        drop(server.requests());
+
+        let _ = make_request(server.port(), "/something");
+
+        assert_eq!(first.recv_timeout(Duration::from_secs(2)).unwrap().url, "/something");
+        assert_eq!(second.recv_timeout(Duration::from_secs(2)).unwrap().url, "/something");
+    }
+
+    #[test]
+    fn should_record_requests_in_arrival_order_when_serialized() {
+        let server = TestServer::new().unwrap();
+        server.create_resource("/order");
+        server.serialized();
+        let requests = server.requests();
+
+        let streams: Vec<TcpStream> = (0..10).map(|i| {
+            let mut stream = TcpStream::connect(format!("127.0.0.1:{}", server.port())).unwrap();
+            stream.write_all(format!("GET /order?i={} HTTP/1.1\r\n\r\n", i).as_bytes()).unwrap();
+            stream
+        }).collect();
+
+        for i in 0..10 {
+            assert_eq!(requests.recv().unwrap().url, format!("/order?i={}", i));
+        }
+
+        drop(streams);
+    }
+
+    #[test]
+    fn should_answer_options_requests_with_allowed_methods() {
+        let server = TestServer::new().unwrap();
+        server.create_resource("/user/{id}");
+        server.create_resource("/user/{id}").method(Method::PUT);
+        server.create_resource("/account").method(Method::OPTIONS).status(Status::OK);
+
+        let mut stream = TcpStream::connect(format!("127.0.0.1:{}", server.port())).unwrap();
+        stream.write_all(b"OPTIONS /user/1 HTTP/1.1\r\n\r\n").unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert_eq!(response, "HTTP/1.1 204 No Content\r\nConnection: close\r\nAllow: GET, PUT\r\n\r\n");
+        assert_eq!(server.resolve_detailed("options", "/user/1"), MatchOutcome::Options(vec!(Method::GET, Method::PUT)));
+        assert_eq!(server.resolve_detailed("OPTIONS", "/account"), MatchOutcome::Matched(String::from("/account")));
+        assert_eq!(server.resolve_detailed("OPTIONS", "/other"), MatchOutcome::NotFound);
+    }
+
+    #[test]
+    fn should_remove_scoped_resources_when_scope_is_dropped() {
+        let server = TestServer::new().unwrap();
+        server.create_resource("/kept");
+
+        {
+            let scope = server.scope();
+            scope.create_resource("/scoped");
+            scope.create_resource("/kept").method(Method::POST);
+
+            assert_eq!(server.resolve_detailed("GET", "/scoped"), MatchOutcome::Matched(String::from("/scoped")));
+        }
+
+        assert_eq!(server.resolve_detailed("GET", "/scoped"), MatchOutcome::NotFound);
+        assert_eq!(server.resolve_detailed("GET", "/kept"), MatchOutcome::Matched(String::from("/kept")));
+        assert_eq!(server.resolve_detailed("POST", "/kept"), MatchOutcome::MethodNotAllowed(vec!(Method::GET)));
+
+        let stream = make_request(server.port(), "/scoped");
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line).unwrap();
+
+        assert_eq!(line, "HTTP/1.1 404 Not Found\r\n");
+    }
+
+    #[test]
+    fn should_ignore_requests_to_ignored_paths() {
+        let server = TestServer::new().unwrap();
+        let requests = server.requests();
+        server.create_resource("/something");
+        server
+            .ignore_path("/favicon.ico")
+            .ignore_path_with_status("/.well-known/*", Status::NotFound);
+
+        let mut favicon = String::new();
+        BufReader::new(make_request(server.port(), "/favicon.ico?v=2")).read_to_string(&mut favicon).unwrap();
+
+        let mut well_known = String::new();
+        BufReader::new(make_request(server.port(), "/.well-known/security.txt")).read_to_string(&mut well_known).unwrap();
+
+        make_request(server.port(), "/something");
+
+        assert_eq!(favicon, "HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n");
+        assert_eq!(well_known, "HTTP/1.1 404 Not Found\r\nConnection: close\r\nContent-Length: 0\r\n\r\n");
+        assert_eq!(requests.recv().unwrap().url, "/something");
+    }
+
+    #[test]
+    fn should_select_resource_matching_header_value() {
+        let server = TestServer::new().unwrap();
+        server.create_resource("/user").match_header("Accept", "application/json").body("json");
+        server.create_resource("/user").match_header("Accept", "application/xml").body("xml");
+        server.create_resource("/user").match_header("X-Debug", "*").body("debug");
+
+        let response = |headers: &str| {
+            let mut stream = TcpStream::connect(format!("127.0.0.1:{}", server.port())).unwrap();
+            stream.write_all(format!("GET /user HTTP/1.1\r\n{}\r\n", headers).as_bytes()).unwrap();
+
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        assert!(response("accept: application/xml\r\n").ends_with("\r\n\r\nxml"));
+        assert!(response("Accept: application/json\r\n").ends_with("\r\n\r\njson"));
+        assert!(response("X-Debug: 1\r\n").ends_with("\r\n\r\ndebug"));
+        assert!(response("Accept: text/html\r\n").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn should_serve_requests_on_same_connection_until_client_closes_it() {
+        let server = TestServer::new().unwrap();
+        server.create_resource("/pooled").method(Method::POST).body_fn(|params| params.body);
+        server.keep_alive();
+
+        let mut stream = TcpStream::connect(format!("127.0.0.1:{}", server.port())).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+        let mut read_response = || {
+            let mut head = vec!();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                head.push(line);
+            }
+            let mut body = [0; 5];
+            reader.read_exact(&mut body).unwrap();
+            (head, String::from_utf8(body.to_vec()).unwrap())
+        };
+
+        for body in &["first", "other", "third"] {
+            stream.write_all(format!("POST /pooled HTTP/1.1\r\nContent-Length: 5\r\n\r\n{}", body).as_bytes()).unwrap();
+            let (head, received) = read_response();
+
+            assert_eq!(&received, body);
+            assert!(!head.contains(&String::from("Connection: close\r\n")));
+        }
+
+        stream.write_all(b"POST /pooled HTTP/1.1\r\nConnection: close\r\nContent-Length: 5\r\n\r\nlast!").unwrap();
+        let (head, received) = read_response();
+
+        assert_eq!(received, "last!");
+        assert!(head.contains(&String::from("Connection: close\r\n")));
+    }
+
+    #[test]
+    fn should_close_connection_after_keep_alive_max_requests() {
+        let server = TestServer::new().unwrap();
+        server.create_resource("/pooled").body("ok");
+        server.keep_alive_max(2);
+
+        let mut stream = TcpStream::connect(format!("127.0.0.1:{}", server.port())).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+        let mut read_response = || {
+            let mut head = vec!();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                head.push(line);
+            }
+            let mut body = [0; 2];
+            reader.read_exact(&mut body).unwrap();
+            head
+        };
+
+        stream.write_all(b"GET /pooled HTTP/1.1\r\n\r\n").unwrap();
+        assert!(!read_response().contains(&String::from("Connection: close\r\n")));
+
+        stream.write_all(b"GET /pooled HTTP/1.1\r\n\r\n").unwrap();
+        assert!(read_response().contains(&String::from("Connection: close\r\n")));
+
+        // connection was closed by the server, either EOF or reset is received
+        let _ = stream.write_all(b"GET /pooled HTTP/1.1\r\n\r\n");
+        let mut rest = String::new();
+        let received = reader.read_to_string(&mut rest).unwrap_or(0);
+        assert_eq!(received, 0);
+
+        let stream = make_request(server.port(), "/pooled");
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line).unwrap();
+        assert_eq!(line, "HTTP/1.1 200 Ok\r\n");
+    }
+
+    #[test]
+    fn should_echo_request() {
+        let server = TestServer::new().unwrap();
+        server.create_echo_resource("/echo");
+
+        let mut stream = TcpStream::connect(format!("127.0.0.1:{}", server.port())).unwrap();
+        stream.write_all(b"POST /echo?debug=1 HTTP/1.0\r\nX-Trace: abc\r\nContent-Length: 12\r\n\r\n{\"name\":\"x\"}").unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 Ok\r\n"));
+        assert!(response.ends_with(
+            "\r\n\r\nPOST /echo?debug=1 HTTP/1.0\r\nContent-Length: 12\r\nX-Trace: abc\r\n\r\n{\"name\":\"x\"}"
+        ));
+    }
+
+    #[test]
+    fn should_count_requests_for_method_and_url() {
+        let server = TestServer::new().unwrap();
+        server.create_resource("/x");
+        server.create_resource("/user/{id}").method(Method::POST);
+
+        for uri in &["/x", "/x?page=2", "/user/1"] {
+            let mut response = String::new();
+            request(server.port(), uri, "GET").read_to_string(&mut response).unwrap();
+        }
+
+        assert_eq!(server.request_count_for("GET", "/x"), 2);
+        assert_eq!(server.request_count_for("POST", "/user/2"), 0);
+        assert_eq!(server.request_count_for("GET", "/user/1"), 0);
+        assert_eq!(server.request_count_for("GET", "/other"), 0);
+    }
+
+    #[test]
+    fn should_not_select_resources_matching_headers_or_body_without_request() {
+        let server = TestServer::new().unwrap();
+        server.create_resource("/tenant").match_header_regex("Content-Type", "text");
+        server.create_resource("/search").method(Method::POST).match_body("query");
+
+        let mut response = String::new();
+        request(server.port(), "/tenant", "GET").read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 Ok\r\n"));
+        assert_eq!(server.request_count_for("GET", "/tenant"), 0);
+        assert_eq!(server.resolve_detailed("GET", "/tenant"), MatchOutcome::NotFound);
+        assert_eq!(server.resolve_detailed("POST", "/search"), MatchOutcome::NotFound);
+    }
+
+    #[test]
+    fn should_fail_to_create_resource_with_invalid_regex() {
+        let server = TestServer::new().unwrap();
+
+        let error = server.try_create_resource("/user/(?<id>.*/x$").err().unwrap();
+
+        assert!(error.to_string().contains("'/user/(?<id>.*/x$'"));
+        assert!(server.unused_resources().is_empty());
+    }
+
+    #[test]
+    fn should_respond_not_found_to_urls_that_are_not_valid_regex() {
+        let server = TestServer::new().unwrap();
+
+        let mut response = String::new();
+        request(server.port(), "/files/(unclosed", "GET").read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn should_match_resource_with_regex() {
+        let server = TestServer::new().unwrap();
+        server.create_resource_with_regex("^/(?<g>.*)/(?<a>.*)/maven-metadata.xml$").body("{path.g}:{path.a}");
+
+        let mut response = String::new();
+        request(server.port(), "/org/example/app/maven-metadata.xml", "GET").read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("\r\n\r\norg/example:app"));
+
+        let mut response = String::new();
+        request(server.port(), "/mirror/org/app/maven-metadata.xml.sha1", "GET").read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn should_skip_resource_when_query_parameter_should_be_absent() {
+        let server = TestServer::new().unwrap();
+        server.create_resource("/items").query_absent("debug").body("plain");
+        server.create_resource("/items?debug=*").body("debug");
+
+        let mut response = String::new();
+        request(server.port(), "/items?debug=1", "GET").read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("\r\n\r\ndebug"));
+
+        let mut response = String::new();
+        request(server.port(), "/items", "GET").read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("\r\n\r\nplain"));
+    }
+
+    #[test]
+    fn should_match_literal_resource_exactly() {
+        let server = TestServer::new().unwrap();
+        server.create_resource_literal("/files/report.pdf").body("report");
+
+        let mut response = String::new();
+        request(server.port(), "/files/report.pdf", "GET").read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("report"));
+
+        for uri in &["/files/reportXpdf", "/files/report.pdf.bak", "/old/files/report.pdf"] {
+            let mut response = String::new();
+            request(server.port(), uri, "GET").read_to_string(&mut response).unwrap();
+            assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", uri);
+        }
+    }
+
+    #[test]
+    fn should_count_all_requests_received_by_server() {
+        let server = TestServer::new().unwrap();
+        let resource = server.create_resource("/x");
+        server.ignore_path("/favicon.ico");
+
+        for uri in &["/x", "/unknown", "/favicon.ico"] {
+            let mut response = String::new();
+            request(server.port(), uri, "GET").read_to_string(&mut response).unwrap();
+        }
+
+        assert_eq!(server.total_request_count(), 2);
+        assert_eq!(resource.request_count(), 1);
+    }
+
+    #[test]
+    fn should_count_requests_not_matching_any_resource() {
+        let server = TestServer::new().unwrap();
+        server.create_resource("/x");
+
+        for uri in &["/x", "/unknown"] {
+            let mut response = String::new();
+            request(server.port(), uri, "GET").read_to_string(&mut response).unwrap();
+        }
+        let mut response = String::new();
+        request(server.port(), "/x", "POST").read_to_string(&mut response).unwrap();
+
+        assert_eq!(server.unmatched_request_count(), 1);
+    }
+
+    #[test]
+    fn should_respond_bad_request_to_malformed_requests() {
+        let server = TestServer::new().unwrap();
+        server.create_resource("/valid");
+
+        for garbage in &[&b"\x00\x01garbage\r\n\r\n"[..], b"GET\r\n\r\n", b"GET / HTTP/1.1\r\nno colon\r\n\r\n"] {
+            let mut stream = TcpStream::connect(format!("127.0.0.1:{}", server.port())).unwrap();
+            stream.write_all(garbage).unwrap();
+
+            let mut line = String::new();
+            BufReader::new(stream).read_line(&mut line).unwrap();
+            assert_eq!(line, "HTTP/1.1 400 Bad Request\r\n");
+        }
+
+        let mut line = String::new();
+        BufReader::new(make_request(server.port(), "/valid")).read_line(&mut line).unwrap();
+        assert_eq!(line, "HTTP/1.1 200 Ok\r\n");
+    }
+
+    #[test]
+    fn should_respond_bad_request_to_body_shorter_than_content_length() {
+        let server = TestServer::new().unwrap();
+        server.create_resource("/upload").method(Method::POST);
+
+        let mut stream = TcpStream::connect(format!("127.0.0.1:{}", server.port())).unwrap();
+        stream.write_all(b"POST /upload HTTP/1.1\r\nContent-Length: 18446744073709551615\r\n\r\nshort").unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert_eq!(response, "HTTP/1.1 400 Bad Request\r\nConnection: close\r\nContent-Length: 0\r\n\r\n");
+    }
+
+    #[test]
+    fn should_respond_payload_too_large_without_reading_body_over_limit() {
+        let server = TestServer::new().unwrap();
+        let resource = server.create_resource("/upload");
+        resource.method(Method::POST).body_fn_with_limit(4, |params| params.body);
+        let requests = server.requests();
+
+        // body is never sent, server must answer from Content-Length alone
+        let mut stream = TcpStream::connect(format!("127.0.0.1:{}", server.port())).unwrap();
+        stream.write_all(b"POST /upload HTTP/1.1\r\nContent-Length: 1000000000\r\n\r\n").unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert_eq!(response, "HTTP/1.1 413 Payload Too Large\r\nConnection: close\r\nContent-Length: 0\r\n\r\n");
+        assert_eq!(resource.request_count(), 1);
+        assert_eq!(requests.recv().unwrap().matched_uri, Some(String::from("/upload")));
+
+        let mut stream = TcpStream::connect(format!("127.0.0.1:{}", server.port())).unwrap();
+        stream.write_all(b"POST /upload HTTP/1.1\r\nContent-Length: 4\r\n\r\nbody").unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert_eq!(response, "HTTP/1.1 200 Ok\r\nConnection: close\r\nContent-Length: 4\r\n\r\nbody");
+    }
+
+    #[test]
+    fn should_close_connection_without_response_when_client_closes_before_request_line() {
+        let server = TestServer::new().unwrap();
+        server.keep_alive_max(2);
+        server.create_resource("/something");
+
+        let mut stream = TcpStream::connect(format!("127.0.0.1:{}", server.port())).unwrap();
+        stream.write_all(b"GET /something HTTP/1.1\r\n\r\n").unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert_eq!(response, "HTTP/1.1 200 Ok\r\nContent-Length: 0\r\n\r\n");
+    }
+
+    #[test]
+    fn should_list_unused_resources() {
+        let server = TestServer::new().unwrap();
+        server.create_resource("/used");
+        server.create_resource("/also-used/{id}");
+        server.create_resource("/unused");
+
+        for uri in &["/used", "/also-used/1"] {
+            let mut response = String::new();
+            request(server.port(), uri, "GET").read_to_string(&mut response).unwrap();
+        }
+
+        assert_eq!(server.unused_resources(), vec!("/unused"));
+    }
+
+    #[test]
+    fn should_allow_multiple_methods_for_same_resource() {
+        let server = TestServer::new().unwrap();
+        let resource = server.create_resource("/user/{id}");
+        resource.methods(&[Method::PUT, Method::PATCH]);
+
+        assert_eq!(server.resolve_detailed("PUT", "/user/1"), MatchOutcome::Matched(String::from("/user/{id}")));
+        assert_eq!(server.resolve_detailed("PATCH", "/user/1"), MatchOutcome::Matched(String::from("/user/{id}")));
+        assert_eq!(server.resolve_detailed("GET", "/user/1"), MatchOutcome::MethodNotAllowed(vec!(Method::PUT, Method::PATCH)));
+    }
+
+    #[test]
+    fn should_answer_head_requests_with_get_resources() {
+        let server = TestServer::new().unwrap();
+        server.create_resource("/user/{id}");
+        server.create_resource("/account").method(Method::PUT);
+
+        assert_eq!(server.resolve_detailed("HEAD", "/user/1"), MatchOutcome::Matched(String::from("/user/{id}")));
+        assert_eq!(server.resolve_detailed("HEAD", "/account"), MatchOutcome::MethodNotAllowed(vec!(Method::PUT)));
+    }
+
+    #[test]
+    fn should_fail_verification_when_body_is_not_expected() {
+        let server = TestServer::new().unwrap();
+        let resource = server.create_resource("/something-else");
+        resource.method(Method::DELETE).expect_no_body();
+
+        let mut stream = TcpStream::connect(format!("127.0.0.1:{}", server.port())).unwrap();
+        stream.write_all(b"DELETE /something-else HTTP/1.1\r\nContent-Length: 2\r\n\r\n{}").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(resource.verify().is_err());
+    }
+
+    #[test]
+    fn should_increment_request_count() {
+        let server = TestServer::new().unwrap();
+        let resource = server.create_resource("/something-else");
+
+        resource.status(Status::OK).body("<some body>");
+
+        assert_eq!(resource.request_count(), 0);
+
+        let _ = make_request(server.port(), "/something-else");
+        let _ = make_request(server.port(), "/something-else");
+
+        thread::sleep(Duration::from_millis(200));
+
+        assert_eq!(resource.request_count(), 2);
+
+    }
+
+    #[test]
+    fn should_remove_resource() {
+        let server = TestServer::new().unwrap();
+        let resource = server.create_resource("/something");
+        server.create_resource("/something").method(Method::POST);
+
+        server.remove_resource(&resource);
+
+        let mut response = String::new();
+        make_request(server.port(), "/something").read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+
+        let mut response = String::new();
+        make_post_request(server.port(), "/something").read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 Ok\r\n"));
+    }
+
+    #[test]
+    fn should_respond_not_found_after_removing_only_resource() {
+        let server = TestServer::new().unwrap();
+        let resource = server.create_resource("/something");
+        resource.status(Status::NotFound).body("custom");
+
+        server.remove_resource(&resource);
+
+        let mut response = String::new();
+        make_request(server.port(), "/something").read_to_string(&mut response).unwrap();
+        assert_eq!(response, "HTTP/1.1 404 Not Found\r\nConnection: close\r\nContent-Length: 0\r\n\r\n");
+    }
+
+    #[test]
+    fn should_reset_request_count() {
+        let server = TestServer::new().unwrap();
+        let resource = server.create_resource("/something-else");
+        resource.body("<some body>");
+
+        let _ = make_request(server.port(), "/something-else");
+        assert!(resource.wait_for_requests(1, Duration::from_secs(2)));
+
+        resource.reset();
+        assert_eq!(resource.request_count(), 0);
+
+        let mut response = String::new();
+        make_request(server.port(), "/something-else").read_to_string(&mut response).unwrap();
+
+        assert_eq!(resource.request_count(), 1);
+        assert!(response.ends_with("<some body>"));
+    }
+
+    #[test]
+    fn should_keep_received_requests() {
+        let server = TestServer::new().unwrap();
+        let resource = server.create_resource("/something-else");
+        resource.method(Method::POST);
+
+        let mut stream = TcpStream::connect(server.address()).unwrap();
+        stream.write_all(b"POST /something-else HTTP/1.1\r\nX-Trace: abc\r\nContent-Length: 5\r\n\r\nhello").unwrap();
+        let _ = make_post_request(server.port(), "/something-else");
+
+        assert!(resource.wait_for_requests(2, Duration::from_secs(2)));
+
+        let requests = resource.requests_received();
+
+        assert_eq!(requests.len() as u32, resource.request_count());
+        assert!(requests.iter().any(|request| request.body == "hello" && request.headers.get("X-Trace").unwrap() == "abc"));
+    }
+
+    #[test]
+    fn should_return_last_request() {
+        let server = TestServer::new().unwrap();
+        let resource = server.create_resource("/something-else");
+        resource.method(Method::POST);
+
+        assert!(resource.last_request().is_none());
+
+        let _ = make_post_request(server.port(), "/something-else");
+        assert!(resource.wait_for_requests(1, Duration::from_secs(2)));
+
+        let request = resource.last_request().unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.url, "/something-else");
+    }
+
+    #[test]
+    fn should_wait_for_requests() {
+        let server = TestServer::new().unwrap();
+        let resource = server.create_resource("/something-else");
+
+        let _ = make_request(server.port(), "/something-else");
+        let _ = make_request(server.port(), "/something-else");
+
+        assert!(resource.wait_for_requests(2, Duration::from_secs(2)));
+        assert_eq!(resource.request_count(), 2);
+    }
+
+    #[test]
+    fn should_stop_waiting_for_requests_after_timeout() {
+        let server = TestServer::new().unwrap();
+        let resource = server.create_resource("/something-else");
+
+        let _ = make_request(server.port(), "/something-else");
+
+        assert!(!resource.wait_for_requests(2, Duration::from_millis(200)));
+        assert_eq!(resource.request_count(), 1);
+    }
+
+    #[test]
+    fn should_assert_resource_was_called_times() {
+        let server = TestServer::new().unwrap();
+        let resource = server.create_resource("/something-else");
+
+        let _ = make_request(server.port(), "/something-else");
+        let _ = make_request(server.port(), "/something-else");
+
+        resource.assert_called_times(2, Duration::from_secs(2));
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected resource '/something-else' to be called 2 times, but it was called 1 times")]
+    fn should_fail_assertion_when_resource_is_not_called_in_time() {
+        let server = TestServer::new().unwrap();
+        let resource = server.create_resource("/something-else");
+
+        let _ = make_request(server.port(), "/something-else");
+
+        resource.assert_called_times(2, Duration::from_millis(200));
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected resource '/something-else' to be called 1 times, but it was called 2 times")]
+    fn should_fail_assertion_when_resource_is_called_more_times() {
+        let server = TestServer::new().unwrap();
+        let resource = server.create_resource("/something-else");
+
+        let _ = make_request(server.port(), "/something-else");
+        let _ = make_request(server.port(), "/something-else");
+        resource.wait_for_requests(2, Duration::from_secs(2));
+
+        resource.assert_called_times(1, Duration::from_millis(200));
+    }
+
+    #[test]
+    fn should_expose_stream() {
+        let server = TestServer::new().unwrap();
+        let resource = server.create_resource("/something-else");
+        resource.stream();
+
+        let (tx, rx) = mpsc::channel();
+
+        let port = server.port();
+
+        thread::spawn(move || {
+            let stream = make_request(port, "/something-else");
+            let reader = BufReader::new(stream);
+
+            for line in reader.lines() {
+                let line = line.unwrap();
+                tx.send(line).unwrap();
+            }
+        });
+
+        thread::sleep(Duration::from_millis(200));
+
+        resource.send_line("hello!");
+        resource.send("it's me");
+        resource.send("\n");
+
+        rx.recv().unwrap();
+        rx.recv().unwrap();
+        assert_eq!(rx.recv().unwrap(), "hello!");
+        assert_eq!(rx.recv().unwrap(), "it's me");
+    }
+
+    #[test]
+    fn should_send_chunked_stream() {
+        let server = TestServer::new().unwrap();
+        let resource = server.create_resource("/stream");
+        resource.stream().chunked().body("initial\n");
+
+        let stream = make_request(server.port(), "/stream");
+        thread::sleep(Duration::from_millis(200));
+
+        resource.send_line("first line").send("").send_line("second line").close_open_connections();
+
+        let mut response = String::new();
+        BufReader::new(stream).read_to_string(&mut response).unwrap();
+
+        let (head, mut body) = response.split_at(response.find("\r\n\r\n").unwrap() + 4);
+        assert!(head.contains("Transfer-Encoding: chunked\r\n"));
+
+        let mut decoded = String::new();
+        loop {
+            let size_end = body.find("\r\n").unwrap();
+            let size = usize::from_str_radix(&body[..size_end], 16).unwrap();
+            let data_start = size_end + 2;
+
+            decoded.push_str(&body[data_start..data_start + size]);
+            assert_eq!(&body[data_start + size..data_start + size + 2], "\r\n");
+            body = &body[data_start + size + 2..];
+
+            if size == 0 {
+                break;
+            }
+        }
+
+        assert_eq!(decoded, "initial\nfirst line\nsecond line\n");
+        assert!(body.is_empty());
+    }
+
+    #[test]
+    fn should_send_binary_data_to_stream() {
+        let server = TestServer::new().unwrap();
+        let resource = server.create_resource("/stream");
+        resource.stream();
+
+        let stream = make_request(server.port(), "/stream");
+        thread::sleep(Duration::from_millis(200));
+
+        resource.send_bytes(&[0, 0, 0, 2, 0xCA, 0xFE]).close_open_connections();
+
+        let mut response = vec!();
+        BufReader::new(stream).read_to_end(&mut response).unwrap();
+
+        assert!(response.ends_with(&[b'\n', 0, 0, 0, 2, 0xCA, 0xFE]));
+    }
+
+    #[test]
+    fn should_delay_stream_writes() {
+        let server = TestServer::new().unwrap();
+        let resource = server.create_resource("/stream");
+        resource.stream().stream_chunk_delay(Duration::from_millis(200));
+
+        let stream = make_request(server.port(), "/stream");
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        while line != "\r\n" {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+        }
+        thread::sleep(Duration::from_millis(100));
+
+        let start = Instant::now();
+        resource.send_line("1").send_line("2").send_line("3");
+
+        let mut lines = vec!();
+        for _ in 0..3 {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            lines.push((line, start.elapsed()));
+        }
+
+        assert_eq!(lines[2].0, "3\n");
+        assert!(lines[0].1 < Duration::from_millis(150));
+        assert!(lines[2].1 >= Duration::from_millis(400));
+    }
+
+    #[test]
+    fn should_close_connection_after_bytes() {
+        let server = TestServer::new().unwrap();
+        server.create_resource("/drop").body("x".repeat(1000)).close_after_bytes(30);
+        server.create_resource("/fine");
+
+        let mut stream = make_request(server.port(), "/drop");
+        let mut received = Vec::new();
+        let mut buffer = [0; 64];
+
+        loop {
+            match stream.read(&mut buffer) {
+                Ok(0) | Err(_) => break,
+                Ok(bytes) => received.extend_from_slice(&buffer[..bytes])
+            }
+        }
+
+        assert_eq!(received, b"HTTP/1.1 200 Ok\r\nContent-Lengt".to_vec());
+
+        let mut response = String::new();
+        make_request(server.port(), "/fine").read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 Ok\r\n"));
+    }
+
+    #[test]
+    fn should_throttle_response() {
+        let server = TestServer::new().unwrap();
+        server.create_resource("/slow").body("x".repeat(10 * 1024)).throttle(10 * 1024);
+
+        let start = Instant::now();
+        let mut response = String::new();
+        make_request(server.port(), "/slow").read_to_string(&mut response).unwrap();
+        let elapsed = start.elapsed();
+
+        assert!(response.ends_with(&"x".repeat(10 * 1024)));
+        assert!(elapsed >= Duration::from_millis(900), "took {:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(3), "took {:?}", elapsed);
+    }
+
+    #[test]
+    fn should_throttle_stream_data() {
+        let server = TestServer::new().unwrap();
+        let resource = server.create_resource("/stream");
+        resource.stream().throttle(1000);
+
+        let stream = make_request(server.port(), "/stream");
+        thread::sleep(Duration::from_millis(200));
+
+        let start = Instant::now();
+        resource.send(&"x".repeat(500)).close_open_connections();
+
+        let mut response = String::new();
+        BufReader::new(stream).read_to_string(&mut response).unwrap();
+
+        assert!(response.ends_with(&"x".repeat(500)));
+        assert!(start.elapsed() >= Duration::from_millis(400));
+    }
+
+    #[test]
+    fn should_drop_slow_stream_clients() {
+        let server = TestServer::new().unwrap();
+        let resource = server.create_resource("/stream");
+        resource.stream().slow_client_policy(SlowClientPolicy::Drop(Duration::from_millis(200)));
+
+        let slow_client = make_request(server.port(), "/stream");
+        let healthy_client = make_request(server.port(), "/stream");
+
+        let reader = thread::spawn(move || {
+            let mut received = 0;
+            let mut buffer = [0; 65536];
+            let mut healthy_client = healthy_client;
+
+            while let Ok(n) = healthy_client.read(&mut buffer) {
+                if n == 0 {
+                    break;
+                }
+                received += n;
+            }
+
+            received
+        });
+
+        thread::sleep(Duration::from_millis(200));
+
+        let data = "x".repeat(1024 * 1024);
+        for _ in 0..32 {
+            resource.send(&data);
+        }
+
+        thread::sleep(Duration::from_millis(1000));
+        resource.send_line("!");
+
+        assert_eq!(resource.open_connections_count(), 1);
+
+        resource.close_open_connections();
+
+        assert!(reader.join().unwrap() >= 32 * 1024 * 1024);
+        drop(slow_client);
+    }
+
+    #[test]
+    fn should_deliver_small_stream_messages_with_tcp_nodelay() {
+        let server = TestServer::new().unwrap();
+        let resource = server.create_resource("/something-else");
+        resource.stream();
+        server.tcp_nodelay(true);
+
+        let (tx, rx) = mpsc::channel();
+        let port = server.port();
+
+        thread::spawn(move || {
+            let stream = make_request(port, "/something-else");
+            let reader = BufReader::new(stream);
+
+            for line in reader.lines() {
+                tx.send(line.unwrap()).unwrap();
+            }
+        });
+
+        rx.recv_timeout(Duration::from_millis(500)).unwrap();
+        rx.recv_timeout(Duration::from_millis(500)).unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        for message in ["a", "b", "c"].iter() {
+            resource.send_line(message);
+            assert_eq!(rx.recv_timeout(Duration::from_millis(100)).unwrap(), *message);
+        }
+    }
+
+    #[test]
+    fn should_write_stream_data_in_chunks() {
+        let server = TestServer::new().unwrap();
+        let resource = server.create_resource("/something-else");
+        resource.stream().stream_chunk_size(4);
+
+        let (tx, rx) = mpsc::channel();
+        let port = server.port();
+
+        thread::spawn(move || {
+            let stream = make_request(port, "/something-else");
+            let reader = BufReader::new(stream);
+
+            for line in reader.lines() {
+                tx.send(line.unwrap()).unwrap();
+            }
+        });
+
+        thread::sleep(Duration::from_millis(200));
+
+        resource.send("abcdefg").send("hij").send_line("klmnopq");
+
+        rx.recv().unwrap();
+        rx.recv().unwrap();
+        assert_eq!(rx.recv().unwrap(), "abcdefghijklmnopq");
+    }
+
+    #[test]
+    fn should_replay_sse_events_after_last_event_id() {
+        let server = TestServer::new().unwrap();
+        let resource = server.create_resource("/events");
+        resource.sse();
+
+        let read_until = |stream: TcpStream, last_line: &str| -> Vec<String> {
+            let mut lines = vec!();
+            for line in BufReader::new(stream).lines() {
+                let line = line.unwrap();
+                let done = line == last_line;
+                lines.push(line);
+                if done {
+                    break;
+                }
+            }
+            lines
+        };
+
+        let stream = make_request(server.port(), "/events");
+        thread::sleep(Duration::from_millis(200));
+
+        resource
+            .push_event("1", "message", "one")
+            .push_event("2", "message", "two")
+            .push_event("3", "message", "three");
+
+        let lines = read_until(stream, "data: three");
+        assert!(lines.contains(&String::from("data: one")));
+
+        let mut stream = TcpStream::connect(format!("127.0.0.1:{}", server.port())).unwrap();
+        stream.write_all(b"GET /events HTTP/1.1\r\nLast-Event-ID: 1\r\n\r\n").unwrap();
+
+        let lines = read_until(stream, "data: three");
+        let body: Vec<&String> = lines.iter().skip_while(|line| !line.is_empty()).skip(1).collect();
+
+        assert_eq!(body, vec!("id: 2", "event: message", "data: two", "", "id: 3", "event: message", "data: three"));
+        assert!(lines.contains(&String::from("Content-Type: text/event-stream")));
+    }
+
+    #[test]
+    fn should_replay_sse_events_after_initial_body() {
+        let server = TestServer::new().unwrap();
+        let resource = server.create_resource("/events");
+        resource.sse().stream_initial_fn(|_| String::from("retry: 1000\n\n"));
+        resource
+            .push_event("1", "message", "one")
+            .push_event("2", "message", "two");
+
+        let mut stream = TcpStream::connect(format!("127.0.0.1:{}", server.port())).unwrap();
+        stream.write_all(b"GET /events HTTP/1.1\r\nLast-Event-ID: 1\r\n\r\n").unwrap();
+        thread::sleep(Duration::from_millis(200));
+        resource.close_open_connections();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(response.ends_with("\r\n\r\nretry: 1000\n\nid: 2\nevent: message\ndata: two\n\n"));
+    }
+
+    #[test]
+    fn should_receive_client_messages_while_streaming() {
+        let server = TestServer::new().unwrap();
+        let resource = server.create_resource("/duplex");
+        let client_messages = resource.stream().client_receiver();
+
+        let mut stream = make_request(server.port(), "/duplex");
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        thread::sleep(Duration::from_millis(200));
+
+        resource.send_line("first");
+        stream.write_all(b"hello from client\n").unwrap();
+
+        assert_eq!(client_messages.recv_timeout(Duration::from_secs(5)).unwrap(), "hello from client");
+
+        resource.send_line("second").close_open_connections();
+
+        let mut response = String::new();
+        reader.read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("\r\n\r\nfirst\nsecond\n"));
+    }
+
+    #[test]
+    fn should_close_client_connections() {
+        let server = TestServer::new().unwrap();
+        let resource = server.create_resource("/something-else");
+        let (tx, rx) = mpsc::channel();
+        let port = server.port();
+
+        resource.stream();
+
+        thread::spawn(move || {
+            let stream = make_request(port, "/something-else");
+            let reader = BufReader::new(stream);
+
+            for _line in reader.lines() {}
+
+            tx.send("connection closed").unwrap();
+            thread::sleep(Duration::from_millis(200));
+        });
 
-        let mut reader = BufReader::new(stream);
-        let mut line = String::new();
-        reader.read_line(&mut line).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        resource.close_open_connections();
 
-        assert_eq!(line, "HTTP/1.1 404 Not Found\r\n");
+        assert_eq!(rx.recv().unwrap(), "connection closed");
     }
 
     #[test]
-    fn server_should_use_random_port() {
+    fn should_return_requests_metadata() {
         let server = TestServer::new().unwrap();
-        let server_2 = TestServer::new().unwrap();
+        let (tx, rx) = mpsc::channel();
+        let port = server.port();
 
-        assert_ne!(server.port(), server_2.port());
-    }
+        thread::spawn(move || {
+            if let Ok(req) = server.requests().recv() {
+                tx.send(req).unwrap();
+                thread::sleep(Duration::from_millis(400));
+            }
+        });
 
-    #[test]
-    fn should_close_connection() {
-        let server = TestServer::new().unwrap();
-        server.close();
+        thread::sleep(Duration::from_millis(100));
+        let _req = make_request(port, "/something-else");
 
-        thread::sleep(Duration::from_millis(200));
+        let mut request_headers = HashMap::new();
+        request_headers.insert(String::from("Content-Type"), String::from("text"));
 
-        let host = format!("127.0.0.1:{}", server.port());
-        let stream = TcpStream::connect(host);
+        let expected_request = Request {
+            url: String::from("/something-else"),
+            method: String::from("GET"),
+            method_enum: Method::GET,
+            version: String::from("HTTP/1.1"),
+            headers: request_headers,
+            body: String::new(),
+            matched_uri: None,
+            request_id: None
+        };
 
-        assert!(stream.is_err());
-        if let Err(e) = stream {
-            assert_eq!(e.kind(), ErrorKind::ConnectionRefused);
-        }
+        assert_eq!(rx.recv().unwrap(), expected_request);
     }
 
     #[test]
-    fn should_handle_multiple_resources() {
+    #[cfg(feature = "serde")]
+    fn should_record_requests_to_dir() {
         let server = TestServer::new().unwrap();
-        let resource = server.create_resource("/this");
-        resource.status(Status::OK).body("<this body>");
-        thread::sleep(Duration::from_millis(200));
-        let resource2 = server.create_resource("/that");
-        resource2.status(Status::OK).body("<that body>");
+        let dir = TempDir::new(&server);
+        server.create_resource("/something-else").method(Method::POST);
+        server.record_to_dir(&dir.0);
 
-        assert_eq!(resource.request_count(), 0);
-        assert_eq!(resource2.request_count(), 0);
+        let mut stream = TcpStream::connect(format!("127.0.0.1:{}", server.port())).unwrap();
+        stream.write_all(b"POST /something-else HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello").unwrap();
 
-        let _ = make_request(server.port(), "/this");
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
 
-        thread::sleep(Duration::from_millis(200));
-        let _ = make_request(server.port(), "/that");
-        thread::sleep(Duration::from_millis(200));
+        let recorded = fs::read_to_string(dir.0.join("request-0001.json")).unwrap();
+        let recorded: serde_json::Value = serde_json::from_str(&recorded).unwrap();
 
-        assert_eq!(resource.request_count(), 1);
-        assert_eq!(resource2.request_count(), 1);
+        assert_eq!(recorded["method"], "POST");
+        assert_eq!(recorded["url"], "/something-else");
+        assert_eq!(recorded["headers"]["Content-Length"], "5");
+        assert_eq!(recorded["body"], "hello");
+        assert_eq!(recorded["matched_uri"], "/something-else");
     }
 
     #[test]
-    fn should_create_resource() {
+    #[cfg(feature = "serde")]
+    fn should_respond_when_requests_cannot_be_recorded() {
         let server = TestServer::new().unwrap();
-        server.create_resource("/something");
+        let dir = TempDir::new(&server);
+        fs::create_dir_all(&dir.0).unwrap();
+        fs::write(dir.0.join("file"), "").unwrap();
+        server.create_resource("/something-else");
+        server.record_to_dir(dir.0.join("file").join("requests"));
 
-        let stream = make_request(server.port(), "/something");
+        let mut response = String::new();
+        make_request(server.port(), "/something-else").read_to_string(&mut response).unwrap();
 
-        let mut reader = BufReader::new(stream);
-        let mut line = String::new();
-        reader.read_line(&mut line).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 Ok\r\n"));
+    }
 
-        assert_eq!(line, "HTTP/1.1 200 Ok\r\n");
+    // removes directory when dropped, even when test fails
+    #[cfg(feature = "serde")]
+    struct TempDir(std::path::PathBuf);
+
+    #[cfg(feature = "serde")]
+    impl TempDir {
+        fn new(server: &TestServer) -> TempDir {
+            TempDir(std::env::temp_dir().join(format!("http-test-server-record-{}-{}", std::process::id(), server.port())))
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
     }
 
     #[test]
-    fn should_return_configured_status_for_resource_resource() {
+    fn should_delay_response() {
         let server = TestServer::new().unwrap();
         let resource = server.create_resource("/something-else");
+        resource.delay(Duration::from_millis(300));
 
-        resource.status(Status::OK);
+        let (tx, rx) = mpsc::channel();
 
-        let stream = make_request(server.port(), "/something-else");
+        let port = server.port();
 
-        let mut reader = BufReader::new(stream);
-        let mut line = String::new();
-        reader.read_line(&mut line).unwrap();
+        thread::spawn(move || {
+            let stream = make_request(port, "/something-else");
+            let reader = BufReader::new(stream);
 
-        assert_eq!(line, "HTTP/1.1 200 Ok\r\n");
+            for line in reader.lines() {
+                let line = line.unwrap();
+                tx.send(line).unwrap();
+            }
+        });
+
+        thread::sleep(Duration::from_millis(200));
+
+        assert!(rx.try_recv().is_err());
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(rx.try_recv().unwrap(), "HTTP/1.1 200 Ok");
     }
 
     #[test]
-    fn should_return_resource_body() {
+    fn should_close_idle_connection_after_keep_alive_timeout() {
         let server = TestServer::new().unwrap();
-        let resource = server.create_resource("/something-else");
+        server.create_resource("/something");
+        server.keep_alive_timeout(Duration::from_millis(100));
 
-        resource.status(Status::OK).body("<some body>");
+        let mut idle_stream = TcpStream::connect(format!("127.0.0.1:{}", server.port())).unwrap();
+        thread::sleep(Duration::from_millis(300));
 
-        let stream = make_request(server.port(), "/something-else");
+        let mut response = Vec::new();
+        idle_stream.read_to_end(&mut response).unwrap();
+        assert!(response.is_empty());
 
+        let stream = make_request(server.port(), "/something");
         let mut reader = BufReader::new(stream);
         let mut line = String::new();
-        reader.read_to_string(&mut line).unwrap();
+        reader.read_line(&mut line).unwrap();
 
-        assert_eq!(line, "HTTP/1.1 200 Ok\r\n\r\n<some body>");
+        assert_eq!(line, "HTTP/1.1 200 Ok\r\n");
     }
 
     #[test]
-    fn should_return_resource_body_with_params() {
+    fn should_keep_request_id_with_each_request() {
         let server = TestServer::new().unwrap();
-        let resource = server.create_resource("/user/{userId}/{thing_id}/{yepyep}");
-
-        resource.status(Status::OK).body("User: {path.userId} Thing: {path.thing_id} Sth: {path.yepyep}");
+        server.create_resource("/traced").with_request_id_header("X-Request-Id");
+        let requests = server.requests();
 
-        let stream = make_request(server.port(), "/user/123/abc/Hello!");
+        for _ in 0..2 {
+            let mut response = String::new();
+            make_request(server.port(), "/traced").read_to_string(&mut response).unwrap();
 
-        let mut reader = BufReader::new(stream);
-        let mut line = String::new();
-        reader.read_to_string(&mut line).unwrap();
+            let id = response.lines().find(|line| line.starts_with("X-Request-Id: ")).unwrap()[14..].to_string();
 
-        assert_eq!(line, "HTTP/1.1 200 Ok\r\n\r\nUser: 123 Thing: abc Sth: Hello!");
+            assert_eq!(requests.recv().unwrap().request_id, Some(id));
+        }
     }
 
     #[test]
-    fn should_work_with_regex_uri() {
+    fn should_release_connections_not_sending_requests_after_read_timeout() {
         let server = TestServer::new().unwrap();
-        let resource = server.create_resource("/hello/[0-9]/[A-z]/.*");
+        server.set_read_timeout(Duration::from_millis(100));
 
-        resource.method(Method::POST).status(Status::OK).body("<some body>");
+        let mut idle_stream = TcpStream::connect(format!("127.0.0.1:{}", server.port())).unwrap();
+        let mut partial_stream = TcpStream::connect(format!("127.0.0.1:{}", server.port())).unwrap();
+        partial_stream.write_all(b"GET /some").unwrap();
 
-        let stream = make_post_request(server.port(), "/hello/8/b/doesntmatter-hehe");
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(server.active_connections(), 2);
 
-        let mut reader = BufReader::new(stream);
-        let mut line = String::new();
-        reader.read_to_string(&mut line).unwrap();
+        thread::sleep(Duration::from_millis(250));
+        assert_eq!(server.active_connections(), 0);
 
-        assert_eq!(line, "HTTP/1.1 200 Ok\r\n\r\n<some body>");
+        let mut response = Vec::new();
+        idle_stream.read_to_end(&mut response).unwrap();
+        partial_stream.read_to_end(&mut response).unwrap();
+        assert!(response.is_empty());
     }
 
-
     #[test]
-    fn should_listen_to_defined_method() {
+    fn should_create_error_resource() {
         let server = TestServer::new().unwrap();
-        let resource = server.create_resource("/something-else");
-
-        resource.method(Method::POST).status(Status::OK).body("<some body>");
+        server.create_error_resource("/broken", Status::InternalServerError, "Unexpected \"error\"");
 
-        let stream = make_post_request(server.port(), "/something-else");
+        let stream = make_request(server.port(), "/broken");
 
         let mut reader = BufReader::new(stream);
         let mut line = String::new();
         reader.read_to_string(&mut line).unwrap();
 
-        assert_eq!(line, "HTTP/1.1 200 Ok\r\n\r\n<some body>");
+        assert_eq!(
+            line,
+            "HTTP/1.1 500 Internal Server Error\r\nConnection: close\r\nContent-Type: application/json\r\nContent-Length: 57\r\n\r\n{\"error\":{\"status\":500,\"message\":\"Unexpected \\\"error\\\"\"}}"
+        );
     }
 
     #[test]
-    fn should_allow_multiple_methods_for_same_uri() {
+    fn should_wait_for_request_matching_predicate() {
         let server = TestServer::new().unwrap();
-        let resource = server.create_resource("/something-else");
-        let resource2 = server.create_resource("/something-else");
-
-        resource.method(Method::GET).status(Status::OK).body("<some body GET>");
-        resource2.method(Method::POST).status(Status::OK).body("<some body POST>");
+        server.create_resource("/webhook").method(Method::POST);
+        let port = server.port();
 
-        let stream_get = make_request(server.port(), "/something-else");
-        let stream_post = make_post_request(server.port(), "/something-else");
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            make_post_request(port, "/webhook");
 
-        let mut reader = BufReader::new(stream_get);
-        let mut line = String::new();
-        reader.read_to_string(&mut line).unwrap();
+            let mut stream = TcpStream::connect(format!("127.0.0.1:{}", port)).unwrap();
+            stream.write_all(b"POST /webhook HTTP/1.1\r\nContent-Length: 4\r\n\r\ndone").unwrap();
+        });
 
-        let mut reader = BufReader::new(stream_post);
-        let mut line2 = String::new();
-        reader.read_to_string(&mut line2).unwrap();
+        let request = server.wait_for_request(|request| request.body == "done", Duration::from_secs(2));
 
-        assert_eq!(line, "HTTP/1.1 200 Ok\r\n\r\n<some body GET>");
-        assert_eq!(line2, "HTTP/1.1 200 Ok\r\n\r\n<some body POST>");
+        assert_eq!(request.unwrap().url, "/webhook");
     }
 
     #[test]
-    fn should_return_405_when_method_not_defined() {
+    fn should_find_request_received_before_waiting() {
         let server = TestServer::new().unwrap();
-        let resource = server.create_resource("/something-else");
-
-        resource.method(Method::POST).status(Status::OK).body("<some body>");
+        server.create_resource("/webhook");
 
-        let stream = make_request(server.port(), "/something-else");
+        let mut response = String::new();
+        make_request(server.port(), "/webhook").read_to_string(&mut response).unwrap();
 
-        let mut reader = BufReader::new(stream);
-        let mut line = String::new();
-        reader.read_to_string(&mut line).unwrap();
+        let request = server.wait_for_request(|request| request.url == "/webhook", Duration::from_millis(10));
 
-        assert_eq!(line, "HTTP/1.1 405 Method Not Allowed\r\n\r\n");
+        assert!(request.is_some());
     }
 
     #[test]
-    fn should_increment_request_count() {
+    fn should_stop_waiting_for_request_after_timeout() {
         let server = TestServer::new().unwrap();
-        let resource = server.create_resource("/something-else");
-
-        resource.status(Status::OK).body("<some body>");
-
-        assert_eq!(resource.request_count(), 0);
-
-        let _ = make_request(server.port(), "/something-else");
-        let _ = make_request(server.port(), "/something-else");
+        server.create_resource("/webhook");
 
-        thread::sleep(Duration::from_millis(200));
-
-        assert_eq!(resource.request_count(), 2);
+        let request = server.wait_for_request(|_| true, Duration::from_millis(100));
 
+        assert!(request.is_none());
     }
 
     #[test]
-    fn should_expose_stream() {
+    fn should_apply_default_delay() {
         let server = TestServer::new().unwrap();
-        let resource = server.create_resource("/something-else");
-        resource.stream();
+        server.create_resource("/something-else");
+        server.default_delay(Duration::from_millis(300));
 
         let (tx, rx) = mpsc::channel();
-
         let port = server.port();
 
         thread::spawn(move || {
@@ -659,102 +3498,137 @@ mod tests {
             let reader = BufReader::new(stream);
 
             for line in reader.lines() {
-                let line = line.unwrap();
-                tx.send(line).unwrap();
+                tx.send(line.unwrap()).unwrap();
             }
         });
 
         thread::sleep(Duration::from_millis(200));
 
-        resource.send_line("hello!");
-        resource.send("it's me");
-        resource.send("\n");
-
-        rx.recv().unwrap();
-        rx.recv().unwrap();
-        assert_eq!(rx.recv().unwrap(), "hello!");
-        assert_eq!(rx.recv().unwrap(), "it's me");
+        assert!(rx.try_recv().is_err());
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(rx.try_recv().unwrap(), "HTTP/1.1 200 Ok");
     }
 
     #[test]
-    fn should_close_client_connections() {
+    fn should_count_active_connections() {
         let server = TestServer::new().unwrap();
-        let resource = server.create_resource("/something-else");
-        let (tx, rx) = mpsc::channel();
-        let port = server.port();
-
+        let resource = server.create_resource("/stream");
         resource.stream();
 
-        thread::spawn(move || {
-            let stream = make_request(port, "/something-else");
-            let reader = BufReader::new(stream);
-
-            for _line in reader.lines() {}
+        let _first = make_request(server.port(), "/stream");
+        let _second = make_request(server.port(), "/stream");
+        thread::sleep(Duration::from_millis(200));
 
-            tx.send("connection closed").unwrap();
-            thread::sleep(Duration::from_millis(200));
-        });
+        assert_eq!(server.active_connections(), 2);
 
-        thread::sleep(Duration::from_millis(100));
         resource.close_open_connections();
+        thread::sleep(Duration::from_millis(200));
 
-        assert_eq!(rx.recv().unwrap(), "connection closed");
+        assert_eq!(server.active_connections(), 0);
     }
 
     #[test]
-    fn should_return_requests_metadata() {
+    fn should_increase_latency_with_active_connections() {
         let server = TestServer::new().unwrap();
-        let (tx, rx) = mpsc::channel();
-        let port = server.port();
+        server.create_resource("/overloaded")
+            .latency_per_connection(Duration::from_millis(100), Duration::from_millis(300));
 
-        thread::spawn(move || {
-            for req in server.requests().iter() {
-                tx.send(req).unwrap();
-                thread::sleep(Duration::from_millis(400));
-                break;
-            }
-        });
+        let start = Instant::now();
+        let mut response = String::new();
+        make_request(server.port(), "/overloaded").read_to_string(&mut response).unwrap();
+        assert!(start.elapsed() < Duration::from_millis(400));
 
+        let host = format!("127.0.0.1:{}", server.port());
+        let streams: Vec<TcpStream> = (0..3).map(|_| TcpStream::connect(&host).unwrap()).collect();
         thread::sleep(Duration::from_millis(100));
-        let _req = make_request(port, "/something-else");
 
-        let mut request_headers = HashMap::new();
-        request_headers.insert(String::from("Content-Type"), String::from("text"));
+        let start = Instant::now();
+        let handles: Vec<_> = streams.into_iter().map(|mut stream| {
+            thread::spawn(move || {
+                stream.write_all(b"GET /overloaded HTTP/1.1\r\n\r\n").unwrap();
+                let mut response = String::new();
+                stream.read_to_string(&mut response).unwrap();
+            })
+        }).collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
 
-        let expected_request = Request {
-            url: String::from("/something-else"),
-            method: String::from("GET"),
-            headers: request_headers
+        assert!(start.elapsed() >= Duration::from_millis(700));
+    }
+
+    #[test]
+    fn should_repeat_delays_with_same_server_seed() {
+        let delays = |server: &TestServer| -> Vec<Duration> {
+            let resource = server.create_resource("/slow");
+            resource.delay_range(Duration::from_millis(0), Duration::from_secs(1));
+
+            (0..10)
+                .map(|_| resource.get_delay(|| splitmix64(&mut server.config.delay_random.lock().unwrap())).unwrap())
+                .collect()
         };
 
-        assert_eq!(rx.recv().unwrap(), expected_request);
+        let server = TestServer::new().unwrap();
+        let other = TestServer::new().unwrap();
+        server.delay_range_seeded(7);
+        other.delay_range_seeded(7);
+
+        let server_delays = delays(&server);
+
+        assert_eq!(server_delays, delays(&other));
+        assert!(server_delays.iter().any(|delay| *delay != server_delays[0]));
     }
 
     #[test]
-    fn should_delay_response() {
+    fn should_prefer_resource_delay_over_default_delay() {
         let server = TestServer::new().unwrap();
-        let resource = server.create_resource("/something-else");
-        resource.delay(Duration::from_millis(300));
+        server.create_resource("/something-else").delay(Duration::from_millis(0));
+        server.default_delay(Duration::from_secs(10));
 
-        let (tx, rx) = mpsc::channel();
+        let stream = make_request(server.port(), "/something-else");
+        stream.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
 
-        let port = server.port();
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
 
-        thread::spawn(move || {
-            let stream = make_request(port, "/something-else");
-            let reader = BufReader::new(stream);
+        assert_eq!(line, "HTTP/1.1 200 Ok\r\n");
+    }
 
-            for line in reader.lines() {
-                let line = line.unwrap();
-                tx.send(line).unwrap();
-            }
-        });
+    #[test]
+    fn should_keep_serving_requests_with_body_starting_with_close() {
+        let server = TestServer::new().unwrap();
+        server.create_resource("/something").method(Method::POST);
 
-        thread::sleep(Duration::from_millis(200));
+        let mut stream = TcpStream::connect(server.address()).unwrap();
+        stream.write_all(b"CLOSE /something HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response);
 
-        assert!(rx.try_recv().is_err());
-        thread::sleep(Duration::from_millis(200));
-        assert_eq!(rx.try_recv().unwrap(), "HTTP/1.1 200 Ok");
+        let mut stream = TcpStream::connect(server.address()).unwrap();
+        stream.write_all(b"POST /something HTTP/1.1\r\nContent-Length: 5\r\n\r\nCLOSE").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 Ok\r\n"));
+
+        let mut response = String::new();
+        make_post_request(server.port(), "/something").read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 Ok\r\n"));
+    }
+
+    #[test]
+    fn server_should_be_closed_after_shutdown() {
+        let server = TestServer::new().unwrap();
+        let address = server.address();
+
+        server.shutdown();
+
+        let stream = TcpStream::connect(address);
+
+        assert_eq!(stream.unwrap_err().kind(), ErrorKind::ConnectionRefused);
     }
 
     #[test]
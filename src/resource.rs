@@ -1,15 +1,30 @@
 //! Server resource builders
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::sync::Condvar;
 use std::sync::mpsc;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::collections::HashMap;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::error::Error;
+use std::fmt;
+use std::convert::TryFrom;
 
 use ::Method;
 use ::Status;
+use ::Request;
 
-use regex::Regex;
+#[cfg(feature = "serde")]
+use serde::Serialize;
+#[cfg(feature = "serde")]
+use serde::de::DeserializeOwned;
+#[cfg(feature = "brotli")]
+use std::io::Write;
+
+use regex::{Captures, Regex, RegexBuilder};
 
 /// Responsible for configuring a resource and interacting with it.
 ///
@@ -46,50 +61,240 @@ use regex::Regex;
 ///
 ///  _Instead, I would suggest creating one resource
 ///  for each behaviour expected. Having said that, I'm not here to judge. Do whatever floats your boat! :)_
-///
-
 pub struct Resource {
+    id: usize,
     uri: String,
-    uri_regex: Regex,
+    uri_kind: UriKind,
+    wildcard_count: usize,
+    uri_regex: Arc<Mutex<Regex>>,
+    case_insensitive: Arc<AtomicBool>,
+    ignore_trailing_slash: Arc<AtomicBool>,
     params: Arc<Mutex<URIParameters>>,
     status_code: Arc<Mutex<Status>>,
     custom_status_code: Arc<Mutex<Option<String>>>,
     headers: Arc<Mutex<HashMap<String, String>>>,
-    body: Arc<Mutex<Option<&'static str>>>,
+    header_builders: Arc<Mutex<Vec<(String, HeaderBuilder)>>>,
+    body: Arc<Mutex<Option<Body>>>,
     body_builder: Arc<Mutex<Option<BodyBuilder>>>, // ᕦ(ò_óˇ)ᕤ
-    method: Arc<Mutex<Method>>,
+    body_builder_limit: Arc<Mutex<Option<usize>>>,
+    try_body_builder: Arc<Mutex<Option<TryBodyBuilder>>>,
+    request_body_builder: Arc<Mutex<Option<RequestBodyBuilder>>>,
+    body_overflow: Arc<Mutex<Option<String>>>,
+    raw_chunks: Arc<Mutex<Option<Vec<RawChunk>>>>,
+    redirect_location: Arc<Mutex<Option<String>>>,
+    responder: Arc<Mutex<Option<Responder>>>,
+    response_sequence: Arc<Mutex<Vec<(Status, String)>>>,
+    response_cursor: Arc<AtomicUsize>,
+    methods: Arc<Mutex<Vec<Method>>>,
+    any_method: Arc<AtomicBool>,
+    header_matchers: Arc<Mutex<Vec<(String, Regex)>>>,
+    body_matchers: Arc<Mutex<Vec<BodyMatcher>>>,
     delay: Arc<Mutex<Option<Duration>>>,
+    delay_range: Arc<Mutex<Option<(Duration, Duration)>>>,
+    latency: Arc<Mutex<Option<(Duration, Duration)>>>,
     request_count: Arc<Mutex<u32>>,
+    request_count_changed: Arc<Condvar>,
+    requests: Arc<Mutex<Vec<Request>>>,
+    expectations: Arc<Mutex<Vec<Expectation>>>,
     is_stream: Arc<AtomicBool>,
-    stream_listeners: Arc<Mutex<Vec<mpsc::Sender<String>>>>
+    is_chunked: Arc<AtomicBool>,
+    stream_chunk_size: Arc<Mutex<Option<usize>>>,
+    stream_chunk_delay: Arc<Mutex<Option<Duration>>>,
+    throttle: Arc<Mutex<Option<usize>>>,
+    close_after_bytes: Arc<Mutex<Option<usize>>>,
+    slow_client_policy: Arc<Mutex<SlowClientPolicy>>,
+    stream_initial: Arc<Mutex<Option<StreamInitial>>>,
+    sse: Arc<AtomicBool>,
+    sse_events: Arc<Mutex<Vec<(String, String)>>>,
+    #[cfg(feature = "brotli")]
+    brotli: Arc<AtomicBool>,
+    stream_listeners: Arc<Mutex<Vec<StreamListener>>>,
+    client_listeners: Arc<Mutex<Vec<mpsc::Sender<String>>>>,
+    request_id_header: Arc<Mutex<Option<String>>>,
+    last_request_id: Arc<Mutex<Option<String>>>
+}
+
+/// Defines what happens to stream clients that don't read data fast enough.
+///
+/// See [`Resource::slow_client_policy`].
+///
+/// [`Resource::slow_client_policy`]: struct.Resource.html#method.slow_client_policy
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SlowClientPolicy {
+    /// Waits until client reads data. Default policy.
+    Block,
+    /// Disconnects client when data can't be written within given timeout.
+    Drop(Duration)
+}
+
+/// Error returned when a resource URI can't be compiled into a regex.
+///
+/// See [`TestServer::try_create_resource`].
+///
+/// [`TestServer::try_create_resource`]: ../struct.TestServer.html#method.try_create_resource
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidUriError {
+    uri: String,
+    reason: String
+}
+
+impl fmt::Display for InvalidUriError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid resource URI '{}': {}", self.uri, self.reason)
+    }
+}
+
+impl Error for InvalidUriError {}
+
+static NEXT_RESOURCE_ID: AtomicUsize = AtomicUsize::new(0);
+
+enum StreamListener {
+    Text(mpsc::Sender<String>),
+    Bytes(mpsc::Sender<Vec<u8>>)
+}
+
+impl StreamListener {
+    fn send(&self, data: &[u8]) -> bool {
+        match self {
+            StreamListener::Text(tx) => tx.send(String::from_utf8_lossy(data).into_owned()).is_ok(),
+            StreamListener::Bytes(tx) => tx.send(data.to_vec()).is_ok()
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum UriKind {
+    /// Regex with `{param}` placeholders and query parameters
+    Template,
+    /// Path matched exactly, except for `{param}` placeholders. Accepts query parameters
+    Literal,
+    /// Regex used as is
+    Regex
 }
 
 struct URIParameters {
     path: Vec<String>,
-    query: HashMap<String, String>
+    query: HashMap<String, String>,
+    absent_query: Vec<String>
+}
+
+enum Body {
+    Text(String),
+    Binary(Vec<u8>),
+    File(PathBuf)
+}
+
+/// Ways of defining response's body. Only one of them can be used per resource.
+#[derive(Clone, Copy, PartialEq)]
+enum BodySource {
+    /// `body`, `body_bytes`, `body_from_file` and `grpc_message`, overwriting each other
+    Content,
+    /// `body_fn`
+    Builder,
+    /// `try_body_fn`
+    TryBuilder,
+    /// `body_fn_req`
+    RequestBuilder,
+    /// `json_fn`
+    Responder
+}
+
+enum Expectation {
+    NoBody,
+    Query(String, String)
 }
 
 type BodyBuilder = Box<dyn Fn(RequestParameters) -> String + Send>;
+type HeaderBuilder = Box<dyn Fn(&Request) -> Option<String> + Send>;
+type TryBodyBuilder = Box<dyn Fn(&Request) -> Result<String, (Status, String)> + Send>;
+type RequestBodyBuilder = Box<dyn Fn(&Request) -> String + Send>;
+type BodyMatcher = Box<dyn Fn(&str) -> bool + Send>;
+type StreamInitial = Box<dyn Fn(&Request) -> String + Send>;
+type RawChunk = (String, Vec<u8>);
+type Responder = Box<dyn Fn(&Request) -> (Status, Vec<u8>) + Send>;
 
 impl Resource {
     pub(crate) fn new(uri: &str) -> Resource {
-        let (uri_regex, params) = create_uri_regex(uri);
+        Resource::try_new(uri).unwrap_or_else(|error| panic!("{}", error))
+    }
 
-        Resource {
+    pub(crate) fn try_new(uri: &str) -> Result<Resource, InvalidUriError> {
+        Resource::create(uri, UriKind::Template)
+    }
+
+    pub(crate) fn new_literal(uri: &str) -> Resource {
+        // escaped patterns always compile
+        Resource::create(uri, UriKind::Literal).unwrap()
+    }
+
+    pub(crate) fn new_regex(pattern: &str) -> Resource {
+        Resource::create(pattern, UriKind::Regex).unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    fn create(uri: &str, uri_kind: UriKind) -> Result<Resource, InvalidUriError> {
+        let (pattern, mut params) = create_uri_pattern(uri, uri_kind);
+        let uri_regex = compile_uri_regex(&pattern, uri_kind, false, false)
+            .map_err(|error| InvalidUriError { uri: String::from(uri), reason: error.to_string() })?;
+
+        // named groups in regex URIs are path parameters, like `{param}`
+        for name in uri_regex.capture_names().flatten() {
+            if !params.path.iter().any(|param| param == name) {
+                params.path.push(String::from(name));
+            }
+        }
+
+        Ok(Resource {
+            id: NEXT_RESOURCE_ID.fetch_add(1, Ordering::Relaxed),
             uri: String::from(uri),
-            uri_regex,
+            uri_kind,
+            wildcard_count: count_wildcards(uri, uri_kind),
+            uri_regex: Arc::new(Mutex::new(uri_regex)),
+            case_insensitive: Arc::new(AtomicBool::new(false)),
+            ignore_trailing_slash: Arc::new(AtomicBool::new(false)),
             params: Arc::new(Mutex::new(params)),
             status_code: Arc::new(Mutex::new(Status::OK)),
             custom_status_code: Arc::new(Mutex::new(None)),
             headers: Arc::new(Mutex::new(HashMap::new())),
+            header_builders: Arc::new(Mutex::new(vec!())),
             body: Arc::new(Mutex::new(None)),
             body_builder: Arc::new(Mutex::new(None)),
-            method: Arc::new(Mutex::new(Method::GET)),
+            body_builder_limit: Arc::new(Mutex::new(None)),
+            try_body_builder: Arc::new(Mutex::new(None)),
+            request_body_builder: Arc::new(Mutex::new(None)),
+            body_overflow: Arc::new(Mutex::new(None)),
+            raw_chunks: Arc::new(Mutex::new(None)),
+            redirect_location: Arc::new(Mutex::new(None)),
+            responder: Arc::new(Mutex::new(None)),
+            response_sequence: Arc::new(Mutex::new(vec!())),
+            response_cursor: Arc::new(AtomicUsize::new(0)),
+            methods: Arc::new(Mutex::new(vec!(Method::GET))),
+            any_method: Arc::new(AtomicBool::new(false)),
+            header_matchers: Arc::new(Mutex::new(vec!())),
+            body_matchers: Arc::new(Mutex::new(vec!())),
             delay: Arc::new(Mutex::new(None)),
+            delay_range: Arc::new(Mutex::new(None)),
+            latency: Arc::new(Mutex::new(None)),
             request_count: Arc::new(Mutex::new(0)),
+            request_count_changed: Arc::new(Condvar::new()),
+            requests: Arc::new(Mutex::new(vec!())),
+            expectations: Arc::new(Mutex::new(vec!())),
             is_stream: Arc::new(AtomicBool::new(false)),
-            stream_listeners: Arc::new(Mutex::new(vec!()))
-        }
+            is_chunked: Arc::new(AtomicBool::new(false)),
+            stream_chunk_size: Arc::new(Mutex::new(None)),
+            stream_chunk_delay: Arc::new(Mutex::new(None)),
+            throttle: Arc::new(Mutex::new(None)),
+            close_after_bytes: Arc::new(Mutex::new(None)),
+            slow_client_policy: Arc::new(Mutex::new(SlowClientPolicy::Block)),
+            stream_initial: Arc::new(Mutex::new(None)),
+            sse: Arc::new(AtomicBool::new(false)),
+            sse_events: Arc::new(Mutex::new(vec!())),
+            #[cfg(feature = "brotli")]
+            brotli: Arc::new(AtomicBool::new(false)),
+            stream_listeners: Arc::new(Mutex::new(vec!())),
+            client_listeners: Arc::new(Mutex::new(vec!())),
+            request_id_header: Arc::new(Mutex::new(None)),
+            last_request_id: Arc::new(Mutex::new(None))
+        })
     }
 
     /// Defines response's HTTP Status .
@@ -141,11 +346,38 @@ impl Resource {
         self
     }
 
+    /// Redirects to given path, keeping the query string of the request.
+    ///
+    /// `Location` header is set to `new_path` followed by request's query string, if any.
+    /// ```
+    /// # use http_test_server::TestServer;
+    /// use http_test_server::http::Status;
+    /// # let server = TestServer::new().unwrap();
+    /// let resource = server.create_resource("/old");
+    ///
+    /// resource.redirect_preserving_query(Status::MovedPermanently, "/new");
+    ///
+    /// // request: GET /old?x=1
+    ///
+    /// // HTTP/1.1 301 Moved Permanently\r\n
+    /// // Location: /new?x=1\r\n
+    /// ```
+    pub fn redirect_preserving_query(&self, status: Status, new_path: &str) -> &Resource {
+        if let Ok(mut location) = self.redirect_location.lock() {
+            *location = Some(String::from(new_path));
+        }
+
+        self.status(status)
+    }
+
     /// Defines response headers.
     ///
     /// Call it multiple times to add multiple headers.
     /// If a header is defined twice only the late value is returned.
     ///
+    /// CR and LF characters are removed from names and values, so headers can't be split or
+    /// injected.
+    ///
     /// ```
     /// # use http_test_server::TestServer;
     /// # let server = TestServer::new().unwrap();
@@ -160,13 +392,196 @@ impl Resource {
         self
     }
 
+    /// Defines a response header computed from the request.
+    ///
+    /// Header is omitted when `builder` returns `None`.
+    ///
+    /// ```
+    /// # use http_test_server::TestServer;
+    /// # let server = TestServer::new().unwrap();
+    /// # let resource = server.create_resource("/i-am-a-resource");
+    /// resource.header_fn_req("X-Signature", |request| {
+    ///     if request.body.is_empty() {
+    ///         None
+    ///     } else {
+    ///         Some(format!("len={}", request.body.len()))
+    ///     }
+    /// });
+    /// ```
+    pub fn header_fn_req(&self, header_name: &str, builder: impl Fn(&Request) -> Option<String> + Send + 'static) -> &Resource {
+        if let Ok(mut header_builders) = self.header_builders.lock() {
+            header_builders.push((String::from(header_name), Box::new(builder)));
+        }
+
+        self
+    }
+
     fn get_headers(&self) -> String {
         let headers = self.headers.lock().unwrap();
         headers.iter().fold(String::new(), | headers, (name, value) | {
-            headers + &format!("{}: {}\r\n", name, value)
+            headers + &format!("{}: {}\r\n", strip_line_breaks(name), strip_line_breaks(value))
         })
     }
 
+    /// Adds a header with a unique id to every response.
+    ///
+    /// A new UUID-like id is generated per request and kept in the request's [`request_id`].
+    /// Useful to verify clients log or propagate correlation ids. The last generated id can be
+    /// retrieved with [`last_request_id`].
+    ///
+    /// ```
+    /// # use http_test_server::TestServer;
+    /// # let server = TestServer::new().unwrap();
+    /// # let resource = server.create_resource("/i-am-a-resource");
+    /// resource.with_request_id_header("X-Request-Id");
+    ///
+    /// // HTTP/1.1 200 Ok\r\n
+    /// // X-Request-Id: 2d8b7c1e-5f0a-4c3e-9b1d-6a7e8f9c0d1e\r\n
+    /// // \r\n
+    /// ```
+    /// [`request_id`]: struct.Request.html#structfield.request_id
+    /// [`last_request_id`]: struct.Resource.html#method.last_request_id
+    pub fn with_request_id_header(&self, name: &str) -> &Resource {
+        if let Ok(mut header) = self.request_id_header.lock() {
+            *header = Some(String::from(name));
+        }
+
+        self
+    }
+
+    /// Last id generated by [`with_request_id_header`]. `None` when no response was sent yet.
+    ///
+    /// ```
+    /// # use http_test_server::TestServer;
+    /// # let server = TestServer::new().unwrap();
+    /// # let resource = server.create_resource("/i-am-a-resource");
+    /// resource.with_request_id_header("X-Request-Id");
+    ///
+    /// assert_eq!(resource.last_request_id(), None);
+    /// ```
+    /// [`with_request_id_header`]: struct.Resource.html#method.with_request_id_header
+    pub fn last_request_id(&self) -> Option<String> {
+        self.last_request_id.lock().unwrap().clone()
+    }
+
+    pub(crate) fn assign_request_id(&self, request: &mut Request) {
+        if self.request_id_header.lock().unwrap().is_some() {
+            let id = generate_request_id();
+            *self.last_request_id.lock().unwrap() = Some(id.clone());
+            request.request_id = Some(id);
+        }
+    }
+
+    /// Only matches requests containing a header with given value.
+    ///
+    /// Use `*` as value to match any value, as long as the header is present.
+    /// Header names are case insensitive. Call it multiple times to require multiple headers.
+    /// When a request does not satisfy it, other resources with the same URI are tried.
+    ///
+    /// ```
+    /// # use http_test_server::TestServer;
+    /// # let server = TestServer::new().unwrap();
+    /// let json = server.create_resource("/user");
+    /// let xml = server.create_resource("/user");
+    ///
+    /// json.match_header("Accept", "application/json").body(r#"{ "name": "John" }"#);
+    /// xml.match_header("Accept", "application/xml").body("<name>John</name>");
+    /// ```
+    pub fn match_header(&self, name: &str, value: &str) -> &Resource {
+        let pattern = if value == "*" { String::from(".*") } else { regex::escape(value) };
+
+        self.match_header_regex(name, &pattern)
+    }
+
+    /// Only matches requests containing a header whose value matches given regex.
+    ///
+    /// The whole header value must match the pattern. Header names are case insensitive.
+    /// When a request does not satisfy it, other resources with the same URI are tried.
+    ///
+    /// ```
+    /// # use http_test_server::TestServer;
+    /// # use http_test_server::http::Status;
+    /// # let server = TestServer::new().unwrap();
+    /// let authorized = server.create_resource("/secret");
+    /// let unauthorized = server.create_resource("/secret");
+    ///
+    /// authorized.match_header_regex("Authorization", "Bearer .+");
+    /// unauthorized.status(Status::Unauthorized);
+    /// ```
+    pub fn match_header_regex(&self, name: &str, pattern: &str) -> &Resource {
+        let regex = match Regex::new(&format!("^(?:{})$", pattern)) {
+            Ok(regex) => regex,
+            Err(error) => panic!("Invalid header pattern '{}': {}", pattern, error)
+        };
+
+        if let Ok(mut matchers) = self.header_matchers.lock() {
+            matchers.push((String::from(name), regex));
+        }
+
+        self
+    }
+
+    /// Only matches requests whose body contains given text.
+    ///
+    /// When a request does not satisfy it, other resources with the same URI are tried.
+    ///
+    /// ```
+    /// # use http_test_server::TestServer;
+    /// # use http_test_server::http::Method;
+    /// # let server = TestServer::new().unwrap();
+    /// let user = server.create_resource("/graphql");
+    /// let posts = server.create_resource("/graphql");
+    ///
+    /// user.method(Method::POST).match_body("query GetUser").body(r#"{ "data": { "user": {} } }"#);
+    /// posts.method(Method::POST).match_body("query GetPosts").body(r#"{ "data": { "posts": [] } }"#);
+    /// ```
+    pub fn match_body(&self, substring: &str) -> &Resource {
+        let substring = String::from(substring);
+
+        self.match_body_fn(move |body| body.contains(&substring))
+    }
+
+    /// Only matches requests whose body satisfies given predicate.
+    ///
+    /// When a request does not satisfy it, other resources with the same URI are tried.
+    ///
+    /// ```
+    /// # use http_test_server::TestServer;
+    /// # use http_test_server::http::Method;
+    /// # let server = TestServer::new().unwrap();
+    /// let resource = server.create_resource("/upload");
+    ///
+    /// resource.method(Method::POST).match_body_fn(|body| body.len() > 1024);
+    /// ```
+    pub fn match_body_fn(&self, predicate: impl Fn(&str) -> bool + Send + 'static) -> &Resource {
+        if let Ok(mut matchers) = self.body_matchers.lock() {
+            matchers.push(Box::new(predicate));
+        }
+
+        self
+    }
+
+    /// Only matches requests whose `Content-Type` is given media type.
+    ///
+    /// Parameters like `charset` and media type case are ignored.
+    /// When a request does not satisfy it, other resources with the same URI are tried.
+    ///
+    /// ```
+    /// # use http_test_server::TestServer;
+    /// # use http_test_server::http::Method;
+    /// # let server = TestServer::new().unwrap();
+    /// let json = server.create_resource("/login");
+    /// let form = server.create_resource("/login");
+    ///
+    /// json.method(Method::POST).match_content_type("application/json");
+    /// form.method(Method::POST).match_content_type("application/x-www-form-urlencoded");
+    /// ```
+    pub fn match_content_type(&self, media_type: &str) -> &Resource {
+        let pattern = format!(r"(?i)\s*{}\s*(;.*)?", regex::escape(media_type.trim()));
+
+        self.match_header_regex("Content-Type", &pattern)
+    }
+
     /// Defines query parameters.
     ///
     /// ```
@@ -186,10 +601,32 @@ impl Resource {
     /// ```
     pub fn query(&self, name: &str, value: &str) -> &Resource {
         let mut params = self.params.lock().unwrap();
+        params.absent_query.retain(|absent| absent != name);
         params.query.insert(String::from(name), String::from(value));
         self
     }
 
+    /// Only matches requests without given query parameter.
+    ///
+    /// ```
+    /// # use http_test_server::TestServer;
+    /// # let server = TestServer::new().unwrap();
+    /// let with_debug = server.create_resource("/i-am-a-resource?debug=*");
+    /// let without_debug = server.create_resource("/i-am-a-resource");
+    ///
+    /// without_debug.query_absent("debug");
+    /// ```
+    pub fn query_absent(&self, name: &str) -> &Resource {
+        let mut params = self.params.lock().unwrap();
+        params.query.remove(name);
+
+        if !params.absent_query.iter().any(|absent| absent == name) {
+            params.absent_query.push(String::from(name));
+        }
+
+        self
+    }
+
     /// Defines response's body.
     ///
     /// If the response is a stream this value will be sent straight after connection.
@@ -210,140 +647,982 @@ impl Resource {
     /// let resource = server.create_resource("/user/{userId}?filter=*");
     /// resource.body("Response for user: {path.userId} filter: {query.filter}");
     /// ```
-    pub fn body(&self, content: &'static str) -> &Resource {
-        if self.body_builder.lock().unwrap().is_some() {
-            panic!("You can't define 'body' when 'body_fn' is already defined");
-        }
+    ///
+    /// Bodies built at runtime are accepted as well:
+    /// ```
+    /// # use http_test_server::TestServer;
+    /// # let server = TestServer::new().unwrap();
+    /// for id in 1..3 {
+    ///     let resource = server.create_resource(&format!("/user/{}", id));
+    ///     resource.body(format!("User {}", id));
+    /// }
+    /// ```
+    pub fn body(&self, content: impl Into<String>) -> &Resource {
+        self.ensure_body_source("body", BodySource::Content);
 
         if let Ok(mut body) = self.body.lock() {
-            *body = Some(content);
+            *body = Some(Body::Text(content.into()));
         }
 
         self
     }
 
-    /// Defines function used to build the response's body.
+    /// Defines response's body as raw bytes.
     ///
-    /// If the response is a stream value will be sent straight after connection.
+    /// Use it for binary content, like images. Path and query parameters are not replaced.
     ///
     /// Calling multiple times will overwrite the previous value.
     ///
     /// ```
     /// # use http_test_server::TestServer;
     /// # let server = TestServer::new().unwrap();
-    /// let resource = server.create_resource("/character/{id}?version=*");
-    /// resource.body_fn(|params| {
-    ///     println!("version: {}", params.query.get("version").unwrap());
-    ///
-    ///     match params.path.get("id").unwrap().as_str() {
-    ///         "Balrog" => r#"{ "message": "YOU SHALL NOT PASS!" }"#.to_string(),
-    ///         _ => r#"{ "message": "Fly, you fools!" }"#.to_string()
-    ///     }
-    /// });
-    ///
+    /// # let resource = server.create_resource("/image.png");
+    /// resource
+    ///     .header("Content-Type", "image/png")
+    ///     .body_bytes(vec!(0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A));
     /// ```
-    pub fn body_fn(&self, builder: impl Fn(RequestParameters) -> String + Send + 'static) -> &Resource {
-        if self.body.lock().unwrap().is_some() {
-            panic!("You can't define 'body_fn' when 'body' is already defined");
-        }
+    pub fn body_bytes(&self, data: Vec<u8>) -> &Resource {
+        self.ensure_body_source("body_bytes", BodySource::Content);
 
-        if let Ok(mut body_builder) = self.body_builder.lock() {
-            *body_builder = Some(Box::new(builder));
+        if let Ok(mut body) = self.body.lock() {
+            *body = Some(Body::Binary(data));
         }
 
         self
     }
 
-    /// Defines HTTP method.
+    /// Compresses response's body with Brotli when client accepts it.
+    ///
+    /// Body is compressed and `Content-Encoding: br` is added only when request's
+    /// `Accept-Encoding` contains `br`. Other clients receive plain body. Either way, response
+    /// has `Vary: Accept-Encoding`. Not applied to streams and [`raw_chunks`]. Requires `brotli`
+    /// feature.
     ///
-    /// A resource will only respond to one method, however multiple resources with same URL and
-    /// different methods can be created.
     /// ```
     /// # use http_test_server::TestServer;
-    /// use http_test_server::http::Method;
     /// # let server = TestServer::new().unwrap();
-    /// let resource_put = server.create_resource("/i-am-a-resource");
-    /// let resource_post = server.create_resource("/i-am-a-resource");
-    ///
-    /// resource_put.method(Method::PUT);
-    /// resource_post.method(Method::POST);
+    /// # let resource = server.create_resource("/i-am-a-resource");
+    /// resource.body("compress me!").brotli();
     /// ```
-    pub fn method(&self, method: Method) -> &Resource {
-        if let Ok(mut m) = self.method.lock() {
-            *m = method;
-        }
+    /// [`raw_chunks`]: struct.Resource.html#method.raw_chunks
+    #[cfg(feature = "brotli")]
+    pub fn brotli(&self) -> &Resource {
+        self.brotli.store(true, Ordering::Relaxed);
 
         self
     }
 
-    pub(crate) fn get_method(&self) -> Method {
-        (*self.method.lock().unwrap()).clone()
+    #[cfg(feature = "brotli")]
+    fn negotiates_brotli(&self) -> bool {
+        // raw chunks are sent verbatim, they would replace compressed body
+        self.brotli.load(Ordering::Relaxed) && !self.is_stream() && self.raw_chunks.lock().unwrap().is_none()
     }
 
-    /// Defines delay to response after client connected
-    /// ```
+    /// Defines response's body as the content of a file.
+    ///
+    /// File is read every time a response is sent, so it can be changed between requests.
+    /// Path and query parameters are not replaced. Responds with `500 Internal Server Error` when
+    /// file can't be read.
+    ///
+    /// ```no_run
     /// # use http_test_server::TestServer;
-    /// use std::time::Duration;
     /// # let server = TestServer::new().unwrap();
-    /// # let resource = server.create_resource("/i-am-a-resource");
-    ///
-    /// resource.delay(Duration::from_millis(500));
+    /// # let resource = server.create_resource("/user");
+    /// resource
+    ///     .header("Content-Type", "application/json")
+    ///     .body_from_file("tests/fixtures/user.json");
     /// ```
-    pub fn delay(&self, delay: Duration) -> &Resource {
-        if let Ok(mut d) = self.delay.lock() {
-            *d = Some(delay);
+    pub fn body_from_file(&self, path: impl AsRef<Path>) -> &Resource {
+        self.ensure_body_source("body_from_file", BodySource::Content);
+
+        if let Ok(mut body) = self.body.lock() {
+            *body = Some(Body::File(path.as_ref().to_path_buf()));
         }
 
         self
     }
 
-    pub(crate) fn get_delay(&self) -> Option<Duration> {
-        *self.delay.lock().unwrap()
+    /// Defines response's body as a gRPC-Web length-prefixed message.
+    ///
+    /// Message is framed as 1 byte compression flag, followed by 4 bytes big-endian message
+    /// length and the message itself. `Content-Type` is set to `application/grpc-web+proto`.
+    ///
+    /// ```
+    /// # use http_test_server::TestServer;
+    /// # let server = TestServer::new().unwrap();
+    /// # let resource = server.create_resource("/i-am-a-resource");
+    /// resource.grpc_message(&[0x08, 0x96, 0x01], false);
+    /// ```
+    pub fn grpc_message(&self, data: &[u8], compressed: bool) -> &Resource {
+        self.ensure_body_source("grpc_message", BodySource::Content);
+
+        let mut message = vec!(compressed as u8);
+        message.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        message.extend_from_slice(data);
+
+        if let Ok(mut body) = self.body.lock() {
+            *body = Some(Body::Binary(message));
+        }
+
+        self.header("Content-Type", "application/grpc-web+proto")
     }
 
-    /// Set response as stream, this means clients won't be disconnected after body is sent and
-    /// updates can be sent and received.
+    /// Defines a sequence of responses.
+    ///
+    /// Each request receives the next response in the sequence. Once exhausted, the last
+    /// response is used for all following requests. Takes precedence over [`status`] and [`body`].
+    ///
+    /// Calling it again starts the new sequence from its first response.
     ///
-    /// See also: [`send`], [`send_line`], [`stream_receiver`].
     /// ```
     /// # use http_test_server::TestServer;
+    /// use http_test_server::http::Status;
     /// # let server = TestServer::new().unwrap();
-    /// let resource = server.create_resource("/stream");
-    ///
-    /// resource.stream();
+    /// let resource = server.create_resource("/flaky");
     ///
-    /// resource
-    ///     .send_line("some")
-    ///     .send_line("data")
-    ///     .close_open_connections();
+    /// resource.responses(&[
+    ///     (Status::ServiceUnavailable, ""),
+    ///     (Status::ServiceUnavailable, ""),
+    ///     (Status::OK, "finally!")
+    /// ]);
     /// ```
-    /// [`send`]: struct.Resource.html#method.send
-    /// [`send_line`]: struct.Resource.html#method.send_line
-    /// [`stream_receiver`]: struct.Resource.html#method.stream_receiver
-    /// [`close_open_connections`]: struct.Resource.html#method.close_open_connections
-    pub fn stream(&self) -> &Resource {
-        self.is_stream.store(true, Ordering::Relaxed);
+    /// [`status`]: struct.Resource.html#method.status
+    /// [`body`]: struct.Resource.html#method.body
+    pub fn responses(&self, responses: &[(Status, &str)]) -> &Resource {
+        if let Ok(mut sequence) = self.response_sequence.lock() {
+            *sequence = responses.iter().map(|(status, body)| (*status, String::from(*body))).collect();
+            self.response_cursor.store(0, Ordering::SeqCst);
+        }
 
         self
     }
 
-    pub(crate) fn is_stream(&self) -> bool {
-        self.is_stream.load(Ordering::Relaxed)
-    }
-
-    fn create_body(&self, uri: &str) -> String {
-        let params = self.extract_params_from_uri(uri);
+    fn next_in_sequence(&self) -> Option<(Status, String)> {
+        let sequence = self.response_sequence.lock().unwrap();
 
-        if let Some(body_builder) = &*self.body_builder.lock().unwrap() {
-            return body_builder(params);
+        if sequence.is_empty() {
+            return None;
         }
 
-        match *self.body.lock().unwrap() {
-            Some(body) => {
-                let mut body = body.to_string();
+        // each response is taken once, even when requests arrive at the same time
+        let index = self.response_cursor.fetch_add(1, Ordering::SeqCst).min(sequence.len() - 1);
 
-                for (name, value) in &params.path {
-                    let key = format!("{{path.{}}}", name);
+        Some(sequence[index].clone())
+    }
+
+    /// Defines function used to build the response's body.
+    ///
+    /// If the response is a stream value will be sent straight after connection.
+    ///
+    /// Calling multiple times will overwrite the previous value.
+    ///
+    /// ```
+    /// # use http_test_server::TestServer;
+    /// # let server = TestServer::new().unwrap();
+    /// let resource = server.create_resource("/character/{id}?version=*");
+    /// resource.body_fn(|params| {
+    ///     println!("version: {}", params.query.get("version").unwrap());
+    ///
+    ///     match params.path.get("id").unwrap().as_str() {
+    ///         "Balrog" => r#"{ "message": "YOU SHALL NOT PASS!" }"#.to_string(),
+    ///         _ => r#"{ "message": "Fly, you fools!" }"#.to_string()
+    ///     }
+    /// });
+    ///
+    /// ```
+    ///
+    /// Request headers and body are available as well, e.g. to build echo endpoints:
+    /// ```
+    /// # use http_test_server::TestServer;
+    /// # let server = TestServer::new().unwrap();
+    /// let resource = server.create_resource("/echo");
+    /// resource.body_fn(|params| {
+    ///     let content_type = params.headers.get("Content-Type").cloned().unwrap_or_default();
+    ///     format!("{}: {}", content_type, params.body)
+    /// });
+    /// ```
+    pub fn body_fn(&self, builder: impl Fn(RequestParameters) -> String + Send + 'static) -> &Resource {
+        self.ensure_body_source("body_fn", BodySource::Builder);
+
+        if let Ok(mut body_builder) = self.body_builder.lock() {
+            *body_builder = Some(Box::new(builder));
+        }
+
+        self
+    }
+
+    /// Same as [`body_fn`], but requests with body larger than `limit` bytes are answered with
+    /// `413 Payload Too Large` without calling `builder`.
+    ///
+    /// Limit is checked against `Content-Length` before reading the body. Server answers without
+    /// reading it and closes the connection. Request is still counted and recorded, with an empty
+    /// body. As body is not read yet, body matchers are not considered when selecting the resource.
+    ///
+    /// ```
+    /// # use http_test_server::TestServer;
+    /// # let server = TestServer::new().unwrap();
+    /// let resource = server.create_resource("/upload");
+    /// resource.body_fn_with_limit(1024, |params| format!("received {} bytes", params.body.len()));
+    ///
+    /// // request: POST /upload with 2KB body
+    ///
+    /// // HTTP/1.1 413 Payload Too Large\r\n
+    /// ```
+    /// [`body_fn`]: struct.Resource.html#method.body_fn
+    pub fn body_fn_with_limit(&self, limit: usize, builder: impl Fn(RequestParameters) -> String + Send + 'static) -> &Resource {
+        self.body_fn(builder);
+
+        if let Ok(mut body_builder_limit) = self.body_builder_limit.lock() {
+            *body_builder_limit = Some(limit);
+        }
+
+        self
+    }
+
+    pub(crate) fn get_body_limit(&self) -> Option<usize> {
+        *self.body_builder_limit.lock().unwrap()
+    }
+
+    /// Defines a fallible function used to build the response's body.
+    ///
+    /// When the function returns `Err((status, body))`, response uses given status and body
+    /// instead of resource's status.
+    ///
+    /// ```
+    /// # use http_test_server::TestServer;
+    /// use http_test_server::http::Status;
+    /// # let server = TestServer::new().unwrap();
+    /// let resource = server.create_resource("/proxy");
+    /// resource.try_body_fn(|request| {
+    ///     match request.headers.get("X-Upstream") {
+    ///         Some(upstream) => Ok(format!("proxied to {}", upstream)),
+    ///         None => Err((Status::BadGateway, String::from("upstream down")))
+    ///     }
+    /// });
+    /// ```
+    pub fn try_body_fn(&self, builder: impl Fn(&Request) -> Result<String, (Status, String)> + Send + 'static) -> &Resource {
+        self.ensure_body_source("try_body_fn", BodySource::TryBuilder);
+
+        if let Ok(mut try_body_builder) = self.try_body_builder.lock() {
+            *try_body_builder = Some(Box::new(builder));
+        }
+
+        self
+    }
+
+    /// Same as [`body_fn`], but `builder` receives the whole [`Request`], including method, URL
+    /// and HTTP version.
+    ///
+    /// ```
+    /// # use http_test_server::TestServer;
+    /// # let server = TestServer::new().unwrap();
+    /// let resource = server.create_resource("/whoami");
+    /// resource.any_method().body_fn_req(|request| format!("{} {}", request.method, request.version));
+    ///
+    /// // request: PUT /whoami HTTP/1.1
+    ///
+    /// // HTTP/1.1 200 Ok\r\n
+    /// // Content-Length: 12\r\n
+    /// // \r\n
+    /// // PUT HTTP/1.1
+    /// ```
+    /// [`body_fn`]: struct.Resource.html#method.body_fn
+    /// [`Request`]: struct.Request.html
+    pub fn body_fn_req(&self, builder: impl Fn(&Request) -> String + Send + 'static) -> &Resource {
+        self.ensure_body_source("body_fn_req", BodySource::RequestBuilder);
+
+        if let Ok(mut request_body_builder) = self.request_body_builder.lock() {
+            *request_body_builder = Some(Box::new(builder));
+        }
+
+        self
+    }
+
+    fn defined_body_source(&self) -> Option<(BodySource, &'static str)> {
+        if self.body_builder.lock().unwrap().is_some() {
+            return Some((BodySource::Builder, "body_fn"));
+        }
+
+        if self.try_body_builder.lock().unwrap().is_some() {
+            return Some((BodySource::TryBuilder, "try_body_fn"));
+        }
+
+        if self.request_body_builder.lock().unwrap().is_some() {
+            return Some((BodySource::RequestBuilder, "body_fn_req"));
+        }
+
+        if self.responder.lock().unwrap().is_some() {
+            return Some((BodySource::Responder, "json_fn"));
+        }
+
+        match *self.body.lock().unwrap() {
+            Some(Body::Text(_)) => Some((BodySource::Content, "body")),
+            Some(Body::Binary(_)) => Some((BodySource::Content, "body_bytes")),
+            Some(Body::File(_)) => Some((BodySource::Content, "body_from_file")),
+            None => None
+        }
+    }
+
+    fn ensure_body_source(&self, name: &str, source: BodySource) {
+        if let Some((defined, defined_name)) = self.defined_body_source() {
+            if defined != source {
+                panic!("You can't define '{}' when '{}' is already defined", name, defined_name);
+            }
+        }
+    }
+
+    /// Sends more bytes than advertised by `Content-Length`.
+    ///
+    /// `Content-Length` header is set to `advertised_len` and `extra` is appended after the body.
+    /// Useful to check how clients deal with surplus data.
+    ///
+    /// ```
+    /// # use http_test_server::TestServer;
+    /// # let server = TestServer::new().unwrap();
+    /// # let resource = server.create_resource("/i-am-a-resource");
+    /// resource.body("hello").overflow_body(5, " and some surplus");
+    ///
+    /// // HTTP/1.1 200 Ok\r\n
+    /// // Content-Length: 5\r\n
+    /// // \r\n
+    /// // hello and some surplus
+    /// ```
+    pub fn overflow_body(&self, advertised_len: usize, extra: &str) -> &Resource {
+        self.header("Content-Length", &advertised_len.to_string());
+
+        if let Ok(mut overflow) = self.body_overflow.lock() {
+            *overflow = Some(String::from(extra));
+        }
+
+        self
+    }
+
+    /// Sends body with `Transfer-Encoding: chunked`, using given size fields verbatim.
+    ///
+    /// Each chunk is sent as `<size>\r\n<data>\r\n`. Sizes are not checked, allowing malformed
+    /// framing on purpose. Useful to check how clients deal with invalid chunked responses.
+    /// Last chunk is not added automatically, use `("0", vec!())` to terminate the body.
+    /// Takes precedence over [`body`].
+    ///
+    /// ```
+    /// # use http_test_server::TestServer;
+    /// # let server = TestServer::new().unwrap();
+    /// # let resource = server.create_resource("/i-am-a-resource");
+    /// resource.raw_chunks(vec!(
+    ///     (String::from("ZZ"), b"data".to_vec()),
+    ///     (String::from("0"), vec!())
+    /// ));
+    ///
+    /// // HTTP/1.1 200 Ok\r\n
+    /// // Transfer-Encoding: chunked\r\n
+    /// // \r\n
+    /// // ZZ\r\n
+    /// // data\r\n
+    /// // 0\r\n
+    /// // \r\n
+    /// ```
+    /// [`body`]: struct.Resource.html#method.body
+    pub fn raw_chunks(&self, chunks: Vec<(String, Vec<u8>)>) -> &Resource {
+        if let Ok(mut raw_chunks) = self.raw_chunks.lock() {
+            *raw_chunks = Some(chunks);
+        }
+
+        self
+    }
+
+    /// Defines function used to build a JSON response from a JSON request body.
+    ///
+    /// Request body is deserialized and passed to the function. Returned value is serialized as
+    /// response body, with the returned status. `Content-Type` is set to `application/json`.
+    ///
+    /// When request body can't be deserialized, server responds `400 Bad Request`. When returned
+    /// value can't be serialized, server responds `500 Internal Server Error`.
+    ///
+    /// Takes precedence over [`status`]. Can't be combined with other body definitions, e.g.
+    /// [`body`] or [`body_fn`].
+    ///
+    /// _Requires `serde` feature._
+    ///
+    /// ```
+    /// # extern crate http_test_server;
+    /// # extern crate serde;
+    /// # use http_test_server::TestServer;
+    /// use http_test_server::http::Status;
+    /// use serde::{Deserialize, Serialize};
+    ///
+    /// #[derive(Deserialize)]
+    /// struct Sum { a: i32, b: i32 }
+    ///
+    /// #[derive(Serialize)]
+    /// struct Result { result: i32 }
+    ///
+    /// # let server = TestServer::new().unwrap();
+    /// let resource = server.create_resource("/sum");
+    /// resource.json_fn(|sum: Sum| (Status::OK, Result { result: sum.a + sum.b }));
+    /// ```
+    /// [`status`]: struct.Resource.html#method.status
+    /// [`body`]: struct.Resource.html#method.body
+    /// [`body_fn`]: struct.Resource.html#method.body_fn
+    #[cfg(feature = "serde")]
+    pub fn json_fn<Req, Res, F>(&self, f: F) -> &Resource
+        where Req: DeserializeOwned, Res: Serialize, F: Fn(Req) -> (Status, Res) + Send + 'static {
+        self.ensure_body_source("json_fn", BodySource::Responder);
+
+        if let Ok(mut responder) = self.responder.lock() {
+            *responder = Some(Box::new(move |request: &Request| {
+                match serde_json::from_str(&request.body) {
+                    Ok(payload) => {
+                        let (status, response) = f(payload);
+
+                        match serde_json::to_vec(&response) {
+                            Ok(body) => (status, body),
+                            Err(_) => (Status::InternalServerError, vec!())
+                        }
+                    },
+                    Err(error) => {
+                        let envelope = serde_json::json!({ "error": { "status": 400, "message": error.to_string() } });
+                        (Status::BadRequest, envelope.to_string().into_bytes())
+                    }
+                }
+            }));
+        }
+
+        self.header("Content-Type", "application/json")
+    }
+
+    /// Exports resource configuration as JSON.
+    ///
+    /// Contains URI, methods (`*` when responding to any method), status, headers and static body.
+    /// Body is `null` when it's not defined or built by a function, and the file path when read from a file.
+    /// ```
+    /// # use http_test_server::TestServer;
+    /// use http_test_server::http::{Method, Status};
+    /// # let server = TestServer::new().unwrap();
+    /// let resource = server.create_resource("/user/{id}");
+    /// resource.method(Method::PUT).status(Status::Created).body("created");
+    ///
+    /// assert_eq!(
+    ///     resource.to_config_json(),
+    ///     r#"{"body":"created","headers":{},"methods":["PUT"],"status":"201 Created","uri":"/user/{id}"}"#
+    /// );
+    /// ```
+    #[cfg(feature = "serde")]
+    pub fn to_config_json(&self) -> String {
+        let methods: Vec<String> = if self.any_method.load(Ordering::Relaxed) {
+            vec!(String::from("*"))
+        } else {
+            self.get_methods().iter().map(|method| method.value().to_string()).collect()
+        };
+
+        let body = match *self.body.lock().unwrap() {
+            Some(Body::Text(ref body)) => Some(body.clone()),
+            Some(Body::Binary(ref body)) => Some(String::from_utf8_lossy(body).into_owned()),
+            Some(Body::File(ref path)) => Some(path.display().to_string()),
+            None => None
+        };
+
+        serde_json::json!({
+            "uri": self.get_uri(),
+            "methods": methods,
+            "status": self.get_status_description(),
+            "headers": *self.headers.lock().unwrap(),
+            "body": body
+        }).to_string()
+    }
+
+    /// Defines HTTP method.
+    ///
+    /// Resource will only respond to this method. See [`methods`] to respond to multiple methods.
+    /// Multiple resources with same URL and different methods can be created.
+    /// ```
+    /// # use http_test_server::TestServer;
+    /// use http_test_server::http::Method;
+    /// # let server = TestServer::new().unwrap();
+    /// let resource_put = server.create_resource("/i-am-a-resource");
+    /// let resource_post = server.create_resource("/i-am-a-resource");
+    ///
+    /// resource_put.method(Method::PUT);
+    /// resource_post.method(Method::POST);
+    /// ```
+    /// [`methods`]: struct.Resource.html#method.methods
+    pub fn method(&self, method: Method) -> &Resource {
+        self.methods(&[method])
+    }
+
+    /// Defines multiple HTTP methods.
+    ///
+    /// Resource responds to requests with any of the given methods.
+    /// ```
+    /// # use http_test_server::TestServer;
+    /// use http_test_server::http::Method;
+    /// # let server = TestServer::new().unwrap();
+    /// let resource = server.create_resource("/i-am-a-resource");
+    ///
+    /// resource.methods(&[Method::PUT, Method::PATCH]).body("updated");
+    /// ```
+    pub fn methods(&self, methods: &[Method]) -> &Resource {
+        if let Ok(mut m) = self.methods.lock() {
+            *m = methods.to_vec();
+        }
+
+        self.any_method.store(false, Ordering::Relaxed);
+
+        self
+    }
+
+    pub(crate) fn is_same(&self, other: &Resource) -> bool {
+        self.id == other.id
+    }
+
+    pub(crate) fn get_uri(&self) -> String {
+        self.uri.clone()
+    }
+
+    pub(crate) fn get_methods(&self) -> Vec<Method> {
+        self.methods.lock().unwrap().clone()
+    }
+
+    /// Makes resource respond to any HTTP method.
+    ///
+    /// Calling [`method`] or [`methods`] afterwards restricts it to those methods again.
+    /// ```
+    /// # use http_test_server::TestServer;
+    /// # let server = TestServer::new().unwrap();
+    /// let resource = server.create_resource("/i-am-a-resource");
+    ///
+    /// resource.any_method().body("Same answer for GET, POST, DELETE...");
+    /// ```
+    /// [`method`]: struct.Resource.html#method.method
+    /// [`methods`]: struct.Resource.html#method.methods
+    pub fn any_method(&self) -> &Resource {
+        self.any_method.store(true, Ordering::Relaxed);
+
+        self
+    }
+
+    pub(crate) fn matches_method(&self, method: &str) -> bool {
+        self.any_method.load(Ordering::Relaxed) || self.get_methods().iter().any(|m| m.equal(method))
+    }
+
+    /// Defines delay to response after client connected
+    /// ```
+    /// # use http_test_server::TestServer;
+    /// use std::time::Duration;
+    /// # let server = TestServer::new().unwrap();
+    /// # let resource = server.create_resource("/i-am-a-resource");
+    ///
+    /// resource.delay(Duration::from_millis(500));
+    /// ```
+    pub fn delay(&self, delay: Duration) -> &Resource {
+        if let Ok(mut d) = self.delay.lock() {
+            *d = Some(delay);
+        }
+
+        self
+    }
+
+    pub(crate) fn get_delay(&self, random: impl FnOnce() -> u64) -> Option<Duration> {
+        if let Some(delay) = *self.delay.lock().unwrap() {
+            return Some(delay);
+        }
+
+        self.delay_range.lock().unwrap().map(|(min, max)| {
+            let span = u64::try_from((max - min).as_nanos()).unwrap_or(u64::MAX);
+            let offset = match span.checked_add(1) {
+                Some(values) => random() % values,
+                None => random()
+            };
+
+            min + Duration::from_nanos(offset)
+        })
+    }
+
+    /// Delays each response by a random duration between `min` and `max`, inclusive.
+    /// Ignored when [`delay`] is defined.
+    ///
+    /// Random durations come from server's generator. Use [`TestServer::delay_range_seeded`] to
+    /// get the same sequence of delays on every run.
+    /// ```
+    /// # use http_test_server::TestServer;
+    /// use std::time::Duration;
+    /// # let server = TestServer::new().unwrap();
+    /// # let resource = server.create_resource("/i-am-a-resource");
+    ///
+    /// resource.delay_range(Duration::from_millis(100), Duration::from_millis(500));
+    /// ```
+    /// [`delay`]: struct.Resource.html#method.delay
+    /// [`TestServer::delay_range_seeded`]: ../struct.TestServer.html#method.delay_range_seeded
+    pub fn delay_range(&self, min: Duration, max: Duration) -> &Resource {
+        if min > max {
+            panic!("Delay range min must not be greater than max");
+        }
+
+        if let Ok(mut delay_range) = self.delay_range.lock() {
+            *delay_range = Some((min, max));
+        }
+
+        self
+    }
+
+    /// Defines response delay growing with server load.
+    ///
+    /// Response is delayed by `base` plus `per_conn` for every other connection open when the
+    /// request is handled. Useful to simulate a degrading server.
+    /// Ignored when [`delay`] is defined.
+    /// ```
+    /// # use http_test_server::TestServer;
+    /// use std::time::Duration;
+    /// # let server = TestServer::new().unwrap();
+    /// # let resource = server.create_resource("/i-am-a-resource");
+    ///
+    /// resource.latency_per_connection(Duration::from_millis(50), Duration::from_millis(100));
+    ///
+    /// // 1 connection: 50ms, 2 connections: 150ms, 3 connections: 250ms...
+    /// ```
+    ///
+    /// [`delay`]: struct.Resource.html#method.delay
+    pub fn latency_per_connection(&self, base: Duration, per_conn: Duration) -> &Resource {
+        if let Ok(mut latency) = self.latency.lock() {
+            *latency = Some((base, per_conn));
+        }
+
+        self
+    }
+
+    pub(crate) fn get_latency(&self, active_connections: usize) -> Option<Duration> {
+        self.latency.lock().unwrap().map(|(base, per_conn)| {
+            base + per_conn * active_connections.saturating_sub(1) as u32
+        })
+    }
+
+    /// Limits how fast response is written to approximately `bytes_per_sec` bytes per second.
+    ///
+    /// Applies to the response and to data sent to stream clients.
+    /// ```
+    /// # use http_test_server::TestServer;
+    /// # let server = TestServer::new().unwrap();
+    /// # let resource = server.create_resource("/i-am-a-resource");
+    ///
+    /// resource.body("slow body").throttle(1024);
+    /// ```
+    pub fn throttle(&self, bytes_per_sec: usize) -> &Resource {
+        if bytes_per_sec == 0 {
+            panic!("Throttle must be greater than zero");
+        }
+
+        if let Ok(mut throttle) = self.throttle.lock() {
+            *throttle = Some(bytes_per_sec);
+        }
+
+        self
+    }
+
+    pub(crate) fn get_throttle(&self) -> Option<usize> {
+        *self.throttle.lock().unwrap()
+    }
+
+    /// Writes only the first `bytes` bytes of the response, status line and headers included,
+    /// and then closes the connection abruptly. Useful to simulate connection drops.
+    /// ```
+    /// # use http_test_server::TestServer;
+    /// # let server = TestServer::new().unwrap();
+    /// # let resource = server.create_resource("/i-am-a-resource");
+    ///
+    /// resource.body("this body won't be fully sent").close_after_bytes(40);
+    /// ```
+    pub fn close_after_bytes(&self, bytes: usize) -> &Resource {
+        if let Ok(mut close_after_bytes) = self.close_after_bytes.lock() {
+            *close_after_bytes = Some(bytes);
+        }
+
+        self
+    }
+
+    pub(crate) fn get_close_after_bytes(&self) -> Option<usize> {
+        *self.close_after_bytes.lock().unwrap()
+    }
+
+    /// Set response as stream, this means clients won't be disconnected after body is sent and
+    /// updates can be sent and received.
+    ///
+    /// See also: [`send`], [`send_line`], [`stream_receiver`].
+    /// ```
+    /// # use http_test_server::TestServer;
+    /// # let server = TestServer::new().unwrap();
+    /// let resource = server.create_resource("/stream");
+    ///
+    /// resource.stream();
+    ///
+    /// resource
+    ///     .send_line("some")
+    ///     .send_line("data")
+    ///     .close_open_connections();
+    /// ```
+    /// [`send`]: struct.Resource.html#method.send
+    /// [`send_line`]: struct.Resource.html#method.send_line
+    /// [`stream_receiver`]: struct.Resource.html#method.stream_receiver
+    /// [`close_open_connections`]: struct.Resource.html#method.close_open_connections
+    pub fn stream(&self) -> &Resource {
+        self.is_stream.store(true, Ordering::Relaxed);
+
+        self
+    }
+
+    pub(crate) fn is_stream(&self) -> bool {
+        self.is_stream.load(Ordering::Relaxed)
+    }
+
+    /// Sends stream data using `Transfer-Encoding: chunked`.
+    ///
+    /// Initial body and data sent through [`send`] are written as chunks, and the terminating
+    /// chunk is written when [`close_open_connections`] is called.
+    /// ```
+    /// # use http_test_server::TestServer;
+    /// # let server = TestServer::new().unwrap();
+    /// let resource = server.create_resource("/stream");
+    ///
+    /// resource.stream().chunked();
+    ///
+    /// resource
+    ///     .send_line("some data")
+    ///     .close_open_connections();
+    ///
+    /// // HTTP/1.1 200 Ok\r\n
+    /// // Transfer-Encoding: chunked\r\n
+    /// // \r\n
+    /// // a\r\n
+    /// // some data\n\r\n
+    /// // 0\r\n
+    /// // \r\n
+    /// ```
+    /// [`send`]: struct.Resource.html#method.send
+    /// [`close_open_connections`]: struct.Resource.html#method.close_open_connections
+    pub fn chunked(&self) -> &Resource {
+        self.is_chunked.store(true, Ordering::Relaxed);
+
+        self
+    }
+
+    pub(crate) fn is_chunked(&self) -> bool {
+        self.is_stream() && self.is_chunked.load(Ordering::Relaxed)
+    }
+
+    /// Defines size of each write to stream clients.
+    ///
+    /// Data queued through [`send`] is coalesced and written in chunks of `bytes` bytes. The
+    /// last chunk may be smaller. By default, data is written as it was sent.
+    /// ```
+    /// # use http_test_server::TestServer;
+    /// # let server = TestServer::new().unwrap();
+    /// let resource = server.create_resource("/stream");
+    ///
+    /// resource.stream().stream_chunk_size(8 * 1024);
+    /// ```
+    /// [`send`]: struct.Resource.html#method.send
+    pub fn stream_chunk_size(&self, bytes: usize) -> &Resource {
+        if bytes == 0 {
+            panic!("Stream chunk size must be greater than zero");
+        }
+
+        if let Ok(mut chunk_size) = self.stream_chunk_size.lock() {
+            *chunk_size = Some(bytes);
+        }
+
+        self
+    }
+
+    pub(crate) fn get_stream_chunk_size(&self) -> Option<usize> {
+        *self.stream_chunk_size.lock().unwrap()
+    }
+
+    /// Defines delay between consecutive writes to stream clients.
+    ///
+    /// Data sent while waiting is queued. Unrelated to [`delay`], which is applied before the
+    /// response is sent.
+    /// ```
+    /// # use http_test_server::TestServer;
+    /// use std::time::Duration;
+    /// # let server = TestServer::new().unwrap();
+    /// let resource = server.create_resource("/stream");
+    ///
+    /// resource.stream().stream_chunk_delay(Duration::from_millis(100));
+    /// ```
+    /// [`delay`]: struct.Resource.html#method.delay
+    pub fn stream_chunk_delay(&self, delay: Duration) -> &Resource {
+        if let Ok(mut stream_chunk_delay) = self.stream_chunk_delay.lock() {
+            *stream_chunk_delay = Some(delay);
+        }
+
+        self
+    }
+
+    pub(crate) fn get_stream_chunk_delay(&self) -> Option<Duration> {
+        *self.stream_chunk_delay.lock().unwrap()
+    }
+
+    /// Defines how stream clients not reading data fast enough are handled.
+    ///
+    /// With `SlowClientPolicy::Drop`, a client is disconnected when writing to it takes longer
+    /// than given timeout. Other clients are not affected either way.
+    ///
+    /// See also: [`stream`]
+    /// ```
+    /// # use http_test_server::TestServer;
+    /// use http_test_server::resource::SlowClientPolicy;
+    /// use std::time::Duration;
+    /// # let server = TestServer::new().unwrap();
+    /// let resource = server.create_resource("/stream");
+    ///
+    /// resource
+    ///     .stream()
+    ///     .slow_client_policy(SlowClientPolicy::Drop(Duration::from_millis(500)));
+    /// ```
+    /// [`stream`]: struct.Resource.html#method.stream
+    pub fn slow_client_policy(&self, policy: SlowClientPolicy) -> &Resource {
+        if let Ok(mut slow_client_policy) = self.slow_client_policy.lock() {
+            *slow_client_policy = policy;
+        }
+
+        self
+    }
+
+    pub(crate) fn get_slow_client_policy(&self) -> SlowClientPolicy {
+        *self.slow_client_policy.lock().unwrap()
+    }
+
+    /// Defines initial stream body using a function that receives the request.
+    ///
+    /// Useful to respond differently to reconnections, e.g. when client sends `Last-Event-ID`.
+    /// Takes precedence over [`body`] and [`body_fn`] for stream resources.
+    /// ```
+    /// # use http_test_server::TestServer;
+    /// # let server = TestServer::new().unwrap();
+    /// let resource = server.create_resource("/events");
+    ///
+    /// resource.stream().stream_initial_fn(|request| {
+    ///     match request.headers.get("Last-Event-ID") {
+    ///         Some(id) => format!("id: {}\n", id.parse::<u32>().unwrap() + 1),
+    ///         None => String::from("id: 1\n")
+    ///     }
+    /// });
+    /// ```
+    /// [`body`]: struct.Resource.html#method.body
+    /// [`body_fn`]: struct.Resource.html#method.body_fn
+    pub fn stream_initial_fn(&self, f: impl Fn(&Request) -> String + Send + 'static) -> &Resource {
+        if let Ok(mut stream_initial) = self.stream_initial.lock() {
+            *stream_initial = Some(Box::new(f));
+        }
+
+        self
+    }
+
+    /// Set response as a Server-Sent Events stream.
+    ///
+    /// Sets `Content-Type: text/event-stream` and keeps track of events sent with [`push_event`].
+    /// Clients reconnecting with `Last-Event-ID` header receive events pushed after that id,
+    /// following initial body. Unknown ids replay all events.
+    ///
+    /// See also: [`stream`].
+    /// ```
+    /// # use http_test_server::TestServer;
+    /// # let server = TestServer::new().unwrap();
+    /// let resource = server.create_resource("/events");
+    ///
+    /// resource.sse();
+    ///
+    /// resource
+    ///     .push_event("1", "message", "hello")
+    ///     .push_event("2", "message", "world");
+    /// ```
+    /// [`push_event`]: struct.Resource.html#method.push_event
+    /// [`stream`]: struct.Resource.html#method.stream
+    pub fn sse(&self) -> &Resource {
+        self.sse.store(true, Ordering::Relaxed);
+
+        self.stream()
+            .header("Content-Type", "text/event-stream")
+            .header("Cache-Control", "no-cache")
+    }
+
+    /// Sends a Server-Sent Event to all connected clients and remembers it for reconnections.
+    ///
+    /// Multi-line data is sent as multiple `data` fields.
+    ///
+    /// See also: [`sse`].
+    /// ```
+    /// # use http_test_server::TestServer;
+    /// # let server = TestServer::new().unwrap();
+    /// let resource = server.create_resource("/events");
+    ///
+    /// resource.sse().push_event("1", "update", "{\"status\": \"done\"}");
+    /// // id: 1
+    /// // event: update
+    /// // data: {"status": "done"}
+    /// ```
+    /// [`sse`]: struct.Resource.html#method.sse
+    pub fn push_event(&self, id: &str, event: &str, data: &str) -> &Resource {
+        let message = format_event(Some(event), data, Some(id));
+
+        self.send_and_remember_event(Some(id), &message)
+    }
+
+    /// Sends a Server-Sent Event to all connected clients. `event` and `id` fields are optional.
+    ///
+    /// Multi-line data is sent as multiple `data` fields. Events with id are remembered for
+    /// reconnections, as with [`push_event`]. Sets `Content-Type: text/event-stream` for
+    /// streams without `Content-Type`.
+    ///
+    /// See also: [`sse`].
+    /// ```
+    /// # use http_test_server::TestServer;
+    /// # let server = TestServer::new().unwrap();
+    /// let resource = server.create_resource("/events");
+    ///
+    /// resource.stream().send_event(Some("update"), "line 1\nline 2", None);
+    /// // event: update
+    /// // data: line 1
+    /// // data: line 2
+    /// ```
+    /// [`push_event`]: struct.Resource.html#method.push_event
+    /// [`sse`]: struct.Resource.html#method.sse
+    pub fn send_event(&self, event: Option<&str>, data: &str, id: Option<&str>) -> &Resource {
+        if self.is_stream() && !self.has_header("Content-Type") {
+            self.header("Content-Type", "text/event-stream");
+        }
+
+        let message = format_event(event, data, id);
+
+        self.send_and_remember_event(id, &message)
+    }
+
+    fn send_and_remember_event(&self, id: Option<&str>, message: &str) -> &Resource {
+        // events lock is held while sending, see `subscribe`
+        let mut events = self.sse_events.lock().unwrap();
+
+        if let Some(id) = id {
+            events.push((String::from(id), String::from(message)));
+        }
+
+        self.send(message)
+    }
+
+    fn create_body(&self, request: &Request) -> io::Result<Vec<u8>> {
+        let params = self.extract_params(request);
+
+        if let Some(body_builder) = &*self.body_builder.lock().unwrap() {
+            return Ok(body_builder(params).into_bytes());
+        }
+
+        if let Some(request_body_builder) = &*self.request_body_builder.lock().unwrap() {
+            return Ok(request_body_builder(request).into_bytes());
+        }
+
+        let path = match *self.body.lock().unwrap() {
+            Some(Body::Text(ref body)) => {
+                let mut body = body.clone();
+
+                for (name, value) in &params.path {
+                    let key = format!("{{path.{}}}", name);
                     body = body.replace(&key, value);
                 }
 
@@ -352,25 +1631,33 @@ impl Resource {
                     body = body.replace(&key, value);
                 }
 
-                body
+                return Ok(body.into_bytes());
             },
-            None => {
-                String::from("")
-            }
-        }
+            Some(Body::Binary(ref body)) => return Ok(body.clone()),
+            Some(Body::File(ref path)) => path.clone(),
+            None => return Ok(vec!())
+        };
+
+        // file is read after releasing body lock, so slow or failing reads don't hold it
+        fs::read(path)
     }
 
-    fn extract_params_from_uri(&self, uri: &str) -> RequestParameters {
-        RequestParameters { path: self.extra_path_params(uri), query: extract_query_params(uri) }
+    fn extract_params(&self, request: &Request) -> RequestParameters {
+        RequestParameters {
+            path: self.extra_path_params(&request.url),
+            query: extract_query_params(&request.url),
+            headers: request.headers.clone(),
+            body: request.body.clone()
+        }
     }
 
     fn extra_path_params(&self, uri: &str) -> HashMap<String, String> {
         let mut params = HashMap::new();
 
-        if let Some(values) = self.uri_regex.captures(uri) {
+        if let Some(values) = self.uri_regex.lock().unwrap().captures(uri) {
             for param in &self.params.lock().unwrap().path {
                 if let Some(value) = values.name(param) {
-                    params.insert(String::from(param), String::from(value.as_str()));
+                    params.insert(String::from(param), percent_decode(value.as_str(), false));
                 }
             }
         }
@@ -378,16 +1665,222 @@ impl Resource {
         params
     }
 
-    pub(crate) fn build_response(&self, uri: &str) -> String {
-        format!("HTTP/1.1 {}\r\n{}\r\n{}",
-            self.get_status_description(),
-            self.get_headers(),
-            self.create_body(uri)
-        )
+    pub(crate) fn has_header(&self, header_name: &str) -> bool {
+        self.headers.lock().unwrap().keys().any(|name| name.eq_ignore_ascii_case(header_name))
+    }
+
+    fn needs_content_length(&self, status: &str) -> bool {
+        let has_content_length = self.has_header("Content-Length");
+
+        // 204 and 304 responses must not have Content-Length
+        !has_content_length && !self.is_stream() && self.raw_chunks.lock().unwrap().is_none()
+            && !status.starts_with("204") && !status.starts_with("304")
+    }
+
+    pub(crate) fn build_response(&self, request: &Request) -> Vec<u8> {
+        let (status, body) = match (&*self.responder.lock().unwrap(), self.next_in_sequence()) {
+            (Some(ref responder), _) => {
+                let (status, body) = responder(request);
+                (status.description().to_string(), body)
+            },
+            (None, Some((status, body))) => (status.description().to_string(), body.into_bytes()),
+            (None, None) => {
+                let stream_initial = self.stream_initial.lock().unwrap();
+                let try_body_builder = self.try_body_builder.lock().unwrap();
+
+                match (&*stream_initial, &*try_body_builder) {
+                    (Some(ref initial), _) if self.is_stream() => {
+                        (self.get_status_description(), initial(request).into_bytes())
+                    },
+                    (_, Some(ref builder)) => match builder(request) {
+                        Ok(body) => (self.get_status_description(), body.into_bytes()),
+                        Err((status, body)) => (status.description().to_string(), body.into_bytes())
+                    },
+                    _ => match self.create_body(request) {
+                        Ok(body) => (self.get_status_description(), body),
+                        Err(_) => (Status::InternalServerError.description().to_string(), vec!())
+                    }
+                }
+            }
+        };
+
+        let mut headers = self.get_headers();
+
+        if let (Some(ref name), Some(ref id)) = (&*self.request_id_header.lock().unwrap(), &request.request_id) {
+            headers += &format!("{}: {}\r\n", name, id);
+        }
+
+        for (name, builder) in self.header_builders.lock().unwrap().iter() {
+            if let Some(value) = builder(request) {
+                headers += &format!("{}: {}\r\n", strip_line_breaks(name), strip_line_breaks(&value));
+            }
+        }
+
+        if let Some(ref location) = *self.redirect_location.lock().unwrap() {
+            let query = request.url.find('?').map(|start| &request.url[start..]).unwrap_or("");
+            headers += &format!("Location: {}{}\r\n", strip_line_breaks(location), strip_line_breaks(query));
+        }
+
+        #[cfg(feature = "brotli")]
+        let body = if self.negotiates_brotli() {
+            // body depends on Accept-Encoding, caches must not reuse it for other clients
+            if !self.has_header("Vary") {
+                headers += "Vary: Accept-Encoding\r\n";
+            }
+
+            if accepts_encoding(request, "br") {
+                headers += "Content-Encoding: br\r\n";
+                compress_brotli(&body)
+            } else {
+                body
+            }
+        } else {
+            body
+        };
+
+        let body = match *self.raw_chunks.lock().unwrap() {
+            Some(ref chunks) => {
+                headers += "Transfer-Encoding: chunked\r\n";
+                encode_raw_chunks(chunks)
+            },
+            None if self.is_chunked() && request.method_enum != Method::HEAD => {
+                headers += "Transfer-Encoding: chunked\r\n";
+                encode_chunk(&body)
+            },
+            None => body
+        };
+
+        if self.needs_content_length(&status) {
+            headers += &format!("Content-Length: {}\r\n", body.len());
+        }
+
+        let mut response = format!("HTTP/1.1 {}\r\n{}\r\n", status, headers).into_bytes();
+
+        // responses to HEAD requests never have a body
+        if request.method_enum == Method::HEAD {
+            return response;
+        }
+
+        response.extend_from_slice(&body);
+
+        if let Some(ref extra) = *self.body_overflow.lock().unwrap() {
+            response.extend_from_slice(extra.as_bytes());
+        }
+
+        response
     }
 
     pub(crate) fn increment_request_count(&self) {
         *(self.request_count.lock().unwrap()) += 1;
+        self.request_count_changed.notify_all();
+    }
+
+    pub(crate) fn add_request(&self, request: Request) {
+        self.requests.lock().unwrap().push(request);
+    }
+
+    /// Expects requests to this resource to have no body.
+    ///
+    /// Expectations are checked by [`verify`].
+    /// ```
+    /// # use http_test_server::TestServer;
+    /// # let server = TestServer::new().unwrap();
+    /// let resource = server.create_resource("/i-am-a-resource");
+    ///
+    /// resource.expect_no_body();
+    ///
+    /// // ...
+    ///
+    /// assert!(resource.verify().is_ok());
+    /// ```
+    /// [`verify`]: struct.Resource.html#method.verify
+    pub fn expect_no_body(&self) -> &Resource {
+        if let Ok(mut expectations) = self.expectations.lock() {
+            expectations.push(Expectation::NoBody);
+        }
+
+        self
+    }
+
+    /// Expects requests to this resource to have given query parameter value.
+    ///
+    /// Use `*` as value to only expect the parameter to be present.
+    /// Expectations are checked by [`verify`].
+    /// ```
+    /// # use http_test_server::TestServer;
+    /// # let server = TestServer::new().unwrap();
+    /// let resource = server.create_resource("/users");
+    ///
+    /// resource
+    ///     .expect_query("api_version", "2")
+    ///     .expect_query("token", "*");
+    ///
+    /// // ...
+    ///
+    /// assert!(resource.verify().is_ok());
+    /// ```
+    /// [`verify`]: struct.Resource.html#method.verify
+    pub fn expect_query(&self, key: &str, value: &str) -> &Resource {
+        if let Ok(mut expectations) = self.expectations.lock() {
+            expectations.push(Expectation::Query(String::from(key), String::from(value)));
+        }
+
+        self
+    }
+
+    /// Checks requests received against resource's expectations.
+    ///
+    /// Returns `Err` describing every expectation not met.
+    ///
+    /// See also: [`expect_no_body`], [`expect_query`].
+    /// ```
+    /// # use http_test_server::TestServer;
+    /// # let server = TestServer::new().unwrap();
+    /// let resource = server.create_resource("/i-am-a-resource");
+    ///
+    /// resource.expect_no_body();
+    ///
+    /// if let Err(message) = resource.verify() {
+    ///     panic!("{}", message);
+    /// }
+    /// ```
+    /// [`expect_no_body`]: struct.Resource.html#method.expect_no_body
+    /// [`expect_query`]: struct.Resource.html#method.expect_query
+    pub fn verify(&self) -> Result<(), String> {
+        let requests = self.requests.lock().unwrap();
+        let mut failures = vec!();
+
+        for expectation in self.expectations.lock().unwrap().iter() {
+            for request in requests.iter() {
+                match *expectation {
+                    Expectation::NoBody => if !request.body.is_empty() {
+                        failures.push(format!(
+                            "{} {}: expected no body, but received \"{}\"",
+                            request.method, request.url, request.body
+                        ));
+                    },
+                    Expectation::Query(ref key, ref expected) => {
+                        match extract_query_params(&request.url).get(key) {
+                            Some(value) if value == expected || expected == "*" => {},
+                            Some(value) => failures.push(format!(
+                                "{} {}: expected query parameter \"{}\" to be \"{}\", but received \"{}\"",
+                                request.method, request.url, key, expected, value
+                            )),
+                            None => failures.push(format!(
+                                "{} {}: expected query parameter \"{}\"",
+                                request.method, request.url, key
+                            ))
+                        }
+                    }
+                }
+            }
+        }
+
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(failures.join("\n"))
+        }
     }
 
     /// Send data to all connected clients.
@@ -407,17 +1900,26 @@ impl Resource {
     /// [`send_line`]: struct.Resource.html#method.send_line
     /// [`stream`]: struct.Resource.html#method.stream
     pub fn send(&self, data: &str) -> &Resource {
-        if let Ok(mut listeners) = self.stream_listeners.lock() {
-            let mut invalid_listeners = vec!();
-            for (i, listener) in listeners.iter().enumerate() {
-                if listener.send(String::from(data)).is_err() {
-                    invalid_listeners.push(i);
-                }
-            }
+        self.send_bytes(data.as_bytes())
+    }
 
-            for i in invalid_listeners.iter() {
-                listeners.remove(*i);
-            }
+    /// Send binary data to all connected clients.
+    ///
+    /// See also: [`send`], [`stream`].
+    /// ```
+    /// # use http_test_server::TestServer;
+    /// # let server = TestServer::new().unwrap();
+    /// let resource = server.create_resource("/stream");
+    ///
+    /// resource.stream();
+    ///
+    /// resource.send_bytes(&[0, 0, 0, 2, 0xCA, 0xFE]);
+    /// ```
+    /// [`send`]: struct.Resource.html#method.send
+    /// [`stream`]: struct.Resource.html#method.stream
+    pub fn send_bytes(&self, data: &[u8]) -> &Resource {
+        if let Ok(mut listeners) = self.stream_listeners.lock() {
+            listeners.retain(|listener| listener.send(data));
         }
 
         self
@@ -457,7 +1959,6 @@ impl Resource {
     /// resource.close_open_connections();
     /// ```
     /// [`stream`]: struct.Resource.html#method.stream
-
     pub fn close_open_connections(&self) {
         if let Ok(mut listeners) = self.stream_listeners.lock() {
             listeners.clear();
@@ -490,44 +1991,287 @@ impl Resource {
     /// ```no_run
     /// # use http_test_server::TestServer;
     /// # let server = TestServer::new().unwrap();
-    /// let resource = server.create_resource("/stream");
-    /// let receiver = resource.stream().stream_receiver();
-    ///
-    /// let new_message = receiver.recv().unwrap();
+    /// let resource = server.create_resource("/stream");
+    /// let receiver = resource.stream().stream_receiver();
+    ///
+    /// let new_message = receiver.recv().unwrap();
+    ///
+    /// for message in receiver.iter() {
+    ///     println!("Client message: {}", message);
+    /// }
+    /// ```
+    /// [`stream`]: struct.Resource.html#method.stream
+    pub fn stream_receiver(&self) -> mpsc::Receiver<String> {
+        let (tx, rx) = mpsc::channel();
+
+        if let Ok(mut listeners) = self.stream_listeners.lock() {
+            listeners.push(StreamListener::Text(tx));
+        }
+        rx
+    }
+
+    pub(crate) fn stream_bytes_receiver(&self) -> mpsc::Receiver<Vec<u8>> {
+        let (tx, rx) = mpsc::channel();
+
+        if let Ok(mut listeners) = self.stream_listeners.lock() {
+            listeners.push(StreamListener::Bytes(tx));
+        }
+        rx
+    }
+
+    /// Registers a stream client. Returns SSE events to replay to the client, when it reconnects
+    /// with `Last-Event-ID`, and the receiver for data sent afterwards.
+    pub(crate) fn subscribe(&self, request: &Request) -> (Vec<u8>, mpsc::Receiver<Vec<u8>>) {
+        // events are sent holding this lock, so each one is either replayed or received
+        let events = self.sse_events.lock().unwrap();
+
+        let last_event_id = request.headers.iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("Last-Event-ID"))
+            .map(|(_, value)| value.trim());
+
+        let replay = match last_event_id {
+            Some(last_event_id) if self.sse.load(Ordering::Relaxed) => {
+                let start = events.iter()
+                    .position(|(id, _)| id == last_event_id)
+                    .map(|position| position + 1)
+                    .unwrap_or(0);
+
+                events[start..].iter().flat_map(|(_, event)| event.bytes()).collect()
+            },
+            _ => vec!()
+        };
+
+        (replay, self.stream_bytes_receiver())
+    }
+
+    /// Receives lines clients write to an open stream connection, while data is still being
+    /// sent to them.
+    ///
+    /// Each message is a line sent by a client, without line break. Only messages received after
+    /// this method is called are delivered.
+    ///
+    /// See also: [`stream`]
+    /// ```no_run
+    /// # use http_test_server::TestServer;
+    /// # let server = TestServer::new().unwrap();
+    /// let resource = server.create_resource("/duplex");
+    /// let receiver = resource.stream().client_receiver();
+    ///
+    /// resource.send_line("ping");
+    ///
+    /// assert_eq!(receiver.recv().unwrap(), "pong");
+    /// ```
+    /// [`stream`]: struct.Resource.html#method.stream
+    pub fn client_receiver(&self) -> mpsc::Receiver<String> {
+        let (tx, rx) = mpsc::channel();
+
+        if let Ok(mut listeners) = self.client_listeners.lock() {
+            listeners.push(tx);
+        }
+        rx
+    }
+
+    pub(crate) fn notify_client_message(&self, message: &str) {
+        if let Ok(mut listeners) = self.client_listeners.lock() {
+            listeners.retain(|listener| listener.send(String::from(message)).is_ok());
+        }
+    }
+
+    /// Number of requests received
+    /// ```
+    /// # use http_test_server::TestServer;
+    /// # let server = TestServer::new().unwrap();
+    /// # let resource = server.create_resource("/stream");
+    /// assert_eq!(resource.request_count(), 0);
+    /// ```
+    pub fn request_count(&self) -> u32 {
+        *(self.request_count.lock().unwrap())
+    }
+
+    /// Requests received by this resource, in arrival order.
+    ///
+    /// Unlike [`TestServer::requests`], requests received before this method is called are
+    /// included.
+    /// ```
+    /// # use http_test_server::TestServer;
+    /// # let server = TestServer::new().unwrap();
+    /// # let resource = server.create_resource("/endpoint");
+    /// for request in resource.requests_received() {
+    ///     println!("{} {}: {}", request.method, request.url, request.body);
+    /// }
+    /// ```
+    /// [`TestServer::requests`]: ../struct.TestServer.html#method.requests
+    pub fn requests_received(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// Last request received by this resource. `None` until a request arrives.
+    /// ```
+    /// # use http_test_server::TestServer;
+    /// # let server = TestServer::new().unwrap();
+    /// # let resource = server.create_resource("/endpoint");
+    /// assert!(resource.last_request().is_none());
+    /// ```
+    pub fn last_request(&self) -> Option<Request> {
+        self.requests.lock().unwrap().last().cloned()
+    }
+
+    /// Sets request count back to zero and clears received requests.
+    /// Resource configuration, like status, headers and body, is kept.
+    /// ```
+    /// # use http_test_server::TestServer;
+    /// # let server = TestServer::new().unwrap();
+    /// # let resource = server.create_resource("/endpoint");
+    /// resource.reset();
+    ///
+    /// assert_eq!(resource.request_count(), 0);
+    /// ```
+    pub fn reset(&self) {
+        *(self.request_count.lock().unwrap()) = 0;
+        self.requests.lock().unwrap().clear();
+    }
+
+    /// Blocks until resource receives `count` requests or timeout elapses.
+    /// Returns whether `count` was reached.
+    /// ```
+    /// # use http_test_server::TestServer;
+    /// use std::time::Duration;
+    /// # let server = TestServer::new().unwrap();
+    /// # let resource = server.create_resource("/endpoint");
+    /// // requests sent to resource in some other thread
+    ///
+    /// if resource.wait_for_requests(2, Duration::from_millis(100)) {
+    ///     assert!(resource.request_count() >= 2);
+    /// }
+    /// ```
+    pub fn wait_for_requests(&self, count: u32, timeout: Duration) -> bool {
+        let request_count = self.request_count.lock().unwrap();
+        let (request_count, _) = self.request_count_changed
+            .wait_timeout_while(request_count, timeout, |request_count| *request_count < count)
+            .unwrap();
+
+        *request_count >= count
+    }
+
+    /// Waits until resource receives `count` requests, like [`wait_for_requests`], and asserts
+    /// it received exactly `count` requests.
+    ///
+    /// Panics with the actual request count when timeout elapses or count is exceeded.
+    /// ```
+    /// # use http_test_server::TestServer;
+    /// use std::time::Duration;
+    /// # let server = TestServer::new().unwrap();
+    /// # let resource = server.create_resource("/endpoint");
+    /// // no requests sent to resource
+    ///
+    /// resource.assert_called_times(0, Duration::from_millis(100));
+    /// ```
+    /// [`wait_for_requests`]: struct.Resource.html#method.wait_for_requests
+    pub fn assert_called_times(&self, count: u32, timeout: Duration) {
+        let request_count = self.request_count.lock().unwrap();
+        let (request_count, _) = self.request_count_changed
+            .wait_timeout_while(request_count, timeout, |request_count| *request_count < count)
+            .unwrap();
+        // lock is released before panicking, so it doesn't get poisoned
+        let actual = *request_count;
+        drop(request_count);
+
+        if actual != count {
+            panic!(
+                "Expected resource '{}' to be called {} times, but it was called {} times",
+                self.uri, count, actual
+            );
+        }
+    }
+
+    /// Regex pattern compiled from resource's URI, after path and query parameters processing.
+    ///
+    /// Useful to understand why a request is matching, or not, this resource.
+    /// ```
+    /// # use http_test_server::TestServer;
+    /// # let server = TestServer::new().unwrap();
+    /// let resource = server.create_resource("/user/{userId}?filter=*");
+    ///
+    /// assert_eq!(resource.uri_pattern(), "/user/(?P<userId>[^//|/?]+)");
+    /// ```
+    pub fn uri_pattern(&self) -> String {
+        String::from(self.uri_regex.lock().unwrap().as_str())
+    }
+
+    pub(crate) fn matches_uri(&self, uri: &str) -> bool {
+        self.uri_regex.lock().unwrap().is_match(uri) && self.matches_query_parameters(uri)
+    }
+
+    /// Makes URI path match regardless of letter case, e.g. `/Users` matches `/users`.
+    /// ```
+    /// # use http_test_server::TestServer;
+    /// # let server = TestServer::new().unwrap();
+    /// let resource = server.create_resource("/users");
     ///
-    /// for message in receiver.iter() {
-    ///     println!("Client message: {}", message);
-    /// }
+    /// resource.case_insensitive();
     /// ```
-    /// [`stream`]: struct.Resource.html#method.stream
-    pub fn stream_receiver(&self) -> mpsc::Receiver<String> {
-        let (tx, rx) = mpsc::channel();
+    pub fn case_insensitive(&self) -> &Resource {
+        self.case_insensitive.store(true, Ordering::Relaxed);
+        self.update_uri_regex();
 
-        if let Ok(mut listeners) = self.stream_listeners.lock() {
-            listeners.push(tx);
-        }
-        rx
+        self
     }
 
-    /// Number of requests received
+    /// Makes URI path match with or without trailing slash, e.g. `/users` matches `/users/`.
     /// ```
     /// # use http_test_server::TestServer;
     /// # let server = TestServer::new().unwrap();
-    /// # let resource = server.create_resource("/stream");
-    /// assert_eq!(resource.request_count(), 0);
+    /// let resource = server.create_resource("/users/");
+    ///
+    /// resource.ignore_trailing_slash();
     /// ```
-    pub fn request_count(&self) -> u32 {
-        *(self.request_count.lock().unwrap())
+    pub fn ignore_trailing_slash(&self) -> &Resource {
+        self.ignore_trailing_slash.store(true, Ordering::Relaxed);
+        self.update_uri_regex();
+
+        self
     }
 
-    pub(crate) fn matches_uri(&self, uri: &str) -> bool {
-        self.uri_regex.is_match(uri) && self.matches_query_parameters(uri)
+    fn update_uri_regex(&self) {
+        let (pattern, _) = create_uri_pattern(&self.uri, self.uri_kind);
+        let uri_regex = compile_uri_regex(
+            &pattern,
+            self.uri_kind,
+            self.case_insensitive.load(Ordering::Relaxed),
+            self.ignore_trailing_slash.load(Ordering::Relaxed)
+        );
+
+        // URI compiled when resource was created, keep that regex if flags make it invalid
+        if let Ok(uri_regex) = uri_regex {
+            *self.uri_regex.lock().unwrap() = uri_regex;
+        }
+    }
+
+    pub(crate) fn wildcard_count(&self) -> usize {
+        self.wildcard_count
+    }
+
+    pub(crate) fn matches_request(&self, request: &Request) -> bool {
+        self.matches_headers(request) && self.body_matchers.lock().unwrap().iter().all(|matcher| matcher(&request.body))
+    }
+
+    pub(crate) fn matches_headers(&self, request: &Request) -> bool {
+        self.header_matchers.lock().unwrap().iter().all(|(name, pattern)| {
+            request.headers.iter().any(|(header, value)| {
+                header.eq_ignore_ascii_case(name) && pattern.is_match(value)
+            })
+        })
     }
 
     fn matches_query_parameters(&self, uri: &str) -> bool {
         let query_params = extract_query_params(uri);
+        let params = self.params.lock().unwrap();
+
+        if params.absent_query.iter().any(|absent| query_params.contains_key(absent)) {
+            return false;
+        }
 
-        for (expected_key, expected_value) in &self.params.lock().unwrap().query {
+        for (expected_key, expected_value) in &params.query {
             if let Some(value) = query_params.get(expected_key) {
                 if expected_value != value && expected_value != "*" {
                     return false;
@@ -547,76 +2291,359 @@ impl Clone for Resource {
     /// This is useful when working with same Resource across threads.
     fn clone(&self) -> Self {
         Resource {
+            id: self.id,
             uri: self.uri.clone(),
+            uri_kind: self.uri_kind,
+            wildcard_count: self.wildcard_count,
             uri_regex: self.uri_regex.clone(),
+            case_insensitive: self.case_insensitive.clone(),
+            ignore_trailing_slash: self.ignore_trailing_slash.clone(),
             params: self.params.clone(),
             status_code: self.status_code.clone(),
             custom_status_code: self.custom_status_code.clone(),
             headers: self.headers.clone(),
+            header_builders: self.header_builders.clone(),
             body: self.body.clone(),
             body_builder: self.body_builder.clone(),
-            method: self.method.clone(),
+            body_builder_limit: self.body_builder_limit.clone(),
+            try_body_builder: self.try_body_builder.clone(),
+            request_body_builder: self.request_body_builder.clone(),
+            body_overflow: self.body_overflow.clone(),
+            raw_chunks: self.raw_chunks.clone(),
+            redirect_location: self.redirect_location.clone(),
+            responder: self.responder.clone(),
+            response_sequence: self.response_sequence.clone(),
+            response_cursor: self.response_cursor.clone(),
+            methods: self.methods.clone(),
+            any_method: self.any_method.clone(),
+            header_matchers: self.header_matchers.clone(),
+            body_matchers: self.body_matchers.clone(),
             delay: self.delay.clone(),
+            delay_range: self.delay_range.clone(),
+            latency: self.latency.clone(),
             request_count: self.request_count.clone(),
+            request_count_changed: self.request_count_changed.clone(),
+            requests: self.requests.clone(),
+            expectations: self.expectations.clone(),
             is_stream: self.is_stream.clone(),
-            stream_listeners: self.stream_listeners.clone()
+            is_chunked: self.is_chunked.clone(),
+            stream_chunk_size: self.stream_chunk_size.clone(),
+            stream_chunk_delay: self.stream_chunk_delay.clone(),
+            throttle: self.throttle.clone(),
+            close_after_bytes: self.close_after_bytes.clone(),
+            slow_client_policy: self.slow_client_policy.clone(),
+            stream_initial: self.stream_initial.clone(),
+            sse: self.sse.clone(),
+            sse_events: self.sse_events.clone(),
+            #[cfg(feature = "brotli")]
+            brotli: self.brotli.clone(),
+            stream_listeners: self.stream_listeners.clone(),
+            client_listeners: self.client_listeners.clone(),
+            request_id_header: self.request_id_header.clone(),
+            last_request_id: self.last_request_id.clone()
         }
     }
 }
 
 pub struct RequestParameters {
     pub path: HashMap<String, String>,
-    pub query: HashMap<String, String>
+    pub query: HashMap<String, String>,
+    pub headers: HashMap<String, String>,
+    pub body: String
 }
 
 
-fn create_uri_regex(uri: &str) -> (Regex, URIParameters) {
-    let re = Regex::new(r"\{(?P<p>([A-z|0-9|_])+)\}").unwrap();
-    let query_regex = Regex::new(r"\?.*").unwrap();
+fn create_uri_pattern(uri: &str, uri_kind: UriKind) -> (String, URIParameters) {
+    if uri_kind == UriKind::Regex {
+        return (String::from(uri), URIParameters { path: vec!(), query: HashMap::new(), absent_query: vec!() });
+    }
+
+    let literal = uri_kind == UriKind::Literal;
+    let re = Regex::new(r"(?P<s>/?)\{(?P<p>([A-z|0-9|_])+)(?P<m>[?*]?)\}").unwrap();
 
-    let params: Vec<String> = re.captures_iter(uri).filter_map(|cap| {
-        match cap.name("p") {
-            Some(p) => Some(String::from(p.as_str())),
-            None => None
-        }
+    // `?` starts the query string, unless it opens a regex group, e.g. `(?P<name>...)`,
+    // or marks an optional parameter, e.g. `{id?}`
+    let query_start = uri.char_indices()
+        .find(|&(i, c)| c == '?' && (literal || !uri[..i].ends_with('(')) && !uri[i + 1..].starts_with('}'))
+        .map(|(i, _)| i);
+
+    let (path, query_params) = match query_start {
+        Some(i) => (&uri[..i], extract_query_params(&uri[i..])),
+        None => (uri, HashMap::new())
+    };
+
+    let params: Vec<String> = re.captures_iter(path).filter_map(|cap| {
+        cap.name("p").map(|p| String::from(p.as_str()))
     }).collect();
 
-    let query_params = extract_query_params(uri);
+    let pattern = if literal {
+        // only parameters are turned into patterns, everything else is escaped
+        let mut pattern = String::new();
+        let mut last = 0;
+
+        for param in re.captures_iter(path) {
+            let whole = param.get(0).unwrap();
+            pattern.push_str(&regex::escape(&path[last..whole.start()]));
+            pattern.push_str(&param_pattern(&param));
+            last = whole.end();
+        }
+
+        pattern.push_str(&regex::escape(&path[last..]));
+        pattern
+    } else {
+        re.replace_all(path, |param: &Captures| param_pattern(param)).into_owned()
+    };
+
+    (pattern, URIParameters { path: params, query: query_params, absent_query: vec!() })
+}
+
+// `{name}` matches a segment, `{name?}` an optional segment and `{name*}` anything, slashes included
+fn param_pattern(param: &Captures) -> String {
+    let slash = &param["s"];
+    let name = &param["p"];
+
+    match &param["m"] {
+        "?" => format!("(?:{}(?P<{}>[^//|/?]+))?", slash, name),
+        "*" => format!("{}(?P<{}>[^?]*)", slash, name),
+        _ => format!("{}(?P<{}>[^//|/?]+)", slash, name)
+    }
+}
+
+/// Number of path parameters and regex special characters in URI path.
+/// Literal URIs have none, so they are preferred over patterns when routing.
+fn count_wildcards(uri: &str, uri_kind: UriKind) -> usize {
+    let param_regex = Regex::new(r"\{([A-z|0-9|_])+[?*]?\}").unwrap();
+    let path = uri.split('?').next().unwrap_or("");
+    let params = param_regex.find_iter(path).count();
+
+    if uri_kind == UriKind::Literal {
+        return params;
+    }
+
+    let path = param_regex.replace_all(path, "");
+    let mut chars = path.chars().peekable();
+    let mut special_chars = 0;
+
+    while let Some(c) = chars.next() {
+        match c {
+            // escaped characters are matched literally
+            '\\' => {
+                chars.next();
+            },
+            // a lone `.` is most likely part of the path, like in `/report.pdf`
+            '.' if chars.peek().is_some_and(|next| "*+?{".contains(*next)) => special_chars += 1,
+            c if "*+?()[]{}|^$".contains(c) => special_chars += 1,
+            _ => {}
+        }
+    }
+
+    params + special_chars
+}
+
+fn compile_uri_regex(pattern: &str, uri_kind: UriKind, case_insensitive: bool, ignore_trailing_slash: bool) -> Result<Regex, regex::Error> {
+    let literal = uri_kind == UriKind::Literal;
+    let mut pattern = String::from(pattern);
+
+    // patterns aren't anchored, so without the slash they match both forms
+    if ignore_trailing_slash && pattern.len() > 1 {
+        pattern = String::from(pattern.trim_end_matches('/'));
+
+        if literal {
+            pattern.push_str("/?");
+        }
+    }
+
+    if literal {
+        pattern = format!(r"^{}(\?|$)", pattern);
+    }
+
+    RegexBuilder::new(&pattern)
+        .case_insensitive(case_insensitive)
+        .build()
+}
+
+pub(crate) fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+pub(crate) fn time_seed() -> u64 {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|time| time.as_nanos() as u64)
+        .unwrap_or(0);
+
+    nanos ^ (COUNTER.fetch_add(1, Ordering::Relaxed) as u64).rotate_left(32)
+}
+
+fn generate_request_id() -> String {
+    let mut seed = time_seed();
 
-    let pattern = query_regex.replace(uri, "");
-    let pattern = re.replace_all(&pattern, r"(?P<$p>[^//|/?]+)");
+    let high = splitmix64(&mut seed);
+    let low = splitmix64(&mut seed);
 
-    (Regex::new(&pattern).unwrap(), URIParameters { path: params, query: query_params})
+    format!("{:08x}-{:04x}-4{:03x}-{:04x}-{:012x}",
+        high >> 32,
+        (high >> 16) & 0xffff,
+        high & 0x0fff,
+        ((low >> 48) & 0x3fff) | 0x8000,
+        low & 0xffff_ffff_ffff
+    )
 }
 
 fn extract_query_params(uri: &str) -> HashMap<String, String> {
-    let query_regex = Regex::new(r"((?P<qk>[^&]+)=(?P<qv>[^&]+))*").unwrap();
-    let path_regex = Regex::new(r".*\?").unwrap();
-    let only_query_parameters = path_regex.replace(uri, "");
-
-    query_regex.captures_iter(&only_query_parameters).filter_map(|cap| {
-        if let Some(query_key) = cap.name("qk") {
-            let query_value = match cap.name("qv") {
-                Some(v) => String::from(v.as_str()),
-                None => String::from("")
-            };
-            return Some((String::from(query_key.as_str()), query_value));
+    let query = match uri.find('?') {
+        Some(start) => &uri[start + 1..],
+        None => return HashMap::new()
+    };
+
+    // repeated keys: last value wins. Keys without value, e.g. `?debug`, have an empty value
+    query.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let mut key_value = pair.splitn(2, '=');
+            let key = key_value.next().unwrap_or("");
+            let value = key_value.next().unwrap_or("");
+
+            (percent_decode(key, true), percent_decode(value, true))
+        })
+        .collect()
+}
+
+// invalid escapes are kept as they are. `+` means space only in query strings
+fn percent_decode(value: &str, plus_as_space: bool) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            },
+            (b'+', _) if plus_as_space => decoded.push(b' '),
+            (byte, _) => decoded.push(byte)
         }
-        None
-    }).collect()
+
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn strip_line_breaks(value: &str) -> String {
+    value.replace(&['\r', '\n'][..], "")
+}
+
+#[cfg(feature = "brotli")]
+fn accepts_encoding(request: &Request, encoding: &str) -> bool {
+    let accept_encoding = request.headers.iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("Accept-Encoding"))
+        .map(|(_, value)| value.as_str())
+        .unwrap_or("");
+
+    accept_encoding.split(',').any(|coding| {
+        let mut parts = coding.split(';').map(|part| part.trim());
+        let name = parts.next().unwrap_or("");
+        let rejected = parts.any(|param| {
+            param.starts_with("q=") && param[2..].parse::<f32>().map(|q| q == 0.0).unwrap_or(false)
+        });
+
+        (name.eq_ignore_ascii_case(encoding) || name == "*") && !rejected
+    })
+}
+
+#[cfg(feature = "brotli")]
+fn compress_brotli(data: &[u8]) -> Vec<u8> {
+    let mut writer = brotli::CompressorWriter::new(vec!(), 4096, 5, 22);
+    writer.write_all(data).unwrap();
+    writer.into_inner()
+}
+
+fn format_event(event: Option<&str>, data: &str, id: Option<&str>) -> String {
+    let mut message = String::new();
+
+    if let Some(id) = id {
+        message += &format!("id: {}\n", strip_line_breaks(id));
+    }
+
+    if let Some(event) = event {
+        message += &format!("event: {}\n", strip_line_breaks(event));
+    }
+
+    for line in data.split('\n') {
+        message += &format!("data: {}\n", line.trim_end_matches('\r'));
+    }
+
+    message + "\n"
+}
+
+/// Encodes data as a single chunk. Empty data is kept empty, as an empty chunk ends the body.
+pub(crate) fn encode_chunk(data: &[u8]) -> Vec<u8> {
+    if data.is_empty() {
+        return vec!();
+    }
+
+    let mut encoded = format!("{:x}\r\n", data.len()).into_bytes();
+    encoded.extend_from_slice(data);
+    encoded.extend_from_slice(b"\r\n");
+
+    encoded
+}
+
+fn encode_raw_chunks(chunks: &[RawChunk]) -> Vec<u8> {
+    let mut encoded = vec!();
+
+    for (size, data) in chunks {
+        encoded.extend_from_slice(size.as_bytes());
+        encoded.extend_from_slice(b"\r\n");
+        encoded.extend_from_slice(data);
+        encoded.extend_from_slice(b"\r\n");
+    }
+
+    encoded
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::thread;
+    #[cfg(feature = "serde")]
+    use serde::Deserialize;
+
+    fn request(url: &str) -> Request {
+        Request {
+            url: String::from(url),
+            method: String::from("GET"),
+            method_enum: Method::GET,
+            version: String::from("HTTP/1.1"),
+            headers: HashMap::new(),
+            body: String::new(),
+            matched_uri: None,
+            request_id: None
+        }
+    }
+
+    fn response_for(resource: &Resource, uri: &str) -> String {
+        String::from_utf8(resource.build_response(&request(uri))).unwrap()
+    }
 
     #[test]
     fn should_convert_to_response_string() {
         let resource = Resource::new("/");
         resource.status(Status::NotFound);
 
-        assert_eq!(resource.build_response("/"), "HTTP/1.1 404 Not Found\r\n\r\n");
+        assert_eq!(response_for(&resource, "/"), "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n");
     }
 
     #[test]
@@ -624,7 +2651,7 @@ mod tests {
         let resource = Resource::new("/");
         resource.status(Status::Accepted).body("hello!");
 
-        assert_eq!(resource.build_response("/"), "HTTP/1.1 202 Accepted\r\n\r\nhello!");
+        assert_eq!(response_for(&resource, "/"), "HTTP/1.1 202 Accepted\r\nContent-Length: 6\r\n\r\nhello!");
     }
 
     #[test]
@@ -632,7 +2659,7 @@ mod tests {
         let resource = Resource::new("/");
         resource.custom_status(666, "The Number Of The Beast").body("hello!");
 
-        assert_eq!(resource.build_response("/"), "HTTP/1.1 666 The Number Of The Beast\r\n\r\nhello!");
+        assert_eq!(response_for(&resource, "/"), "HTTP/1.1 666 The Number Of The Beast\r\nContent-Length: 6\r\n\r\nhello!");
     }
 
     #[test]
@@ -640,7 +2667,7 @@ mod tests {
         let resource = Resource::new("/");
         resource.custom_status(666, "The Number Of The Beast").status(Status::Forbidden).body("hello!");
 
-        assert_eq!(resource.build_response("/"), "HTTP/1.1 403 Forbidden\r\n\r\nhello!");
+        assert_eq!(response_for(&resource, "/"), "HTTP/1.1 403 Forbidden\r\nContent-Length: 6\r\n\r\nhello!");
     }
 
     #[test]
@@ -650,7 +2677,7 @@ mod tests {
             .header("Content-Type", "application/json")
             .body("hello!");
 
-        assert_eq!(resource.build_response("/"), "HTTP/1.1 200 Ok\r\nContent-Type: application/json\r\n\r\nhello!");
+        assert_eq!(response_for(&resource, "/"), "HTTP/1.1 200 Ok\r\nContent-Type: application/json\r\nContent-Length: 6\r\n\r\nhello!");
     }
 
     #[test]
@@ -661,22 +2688,352 @@ mod tests {
             .header("Connection", "Keep-Alive")
             .body("hello!");
 
-        let response = resource.build_response("/");
+        let response = response_for(&resource, "/");
 
         assert!(response.contains("Content-Type: application/json\r\n"));
         assert!(response.contains("Connection: Keep-Alive\r\n"));
     }
 
+    #[test]
+    fn should_add_unique_request_id_header() {
+        let resource = Resource::new("/");
+        resource.with_request_id_header("X-Request-Id");
+
+        let id_regex = Regex::new(r"X-Request-Id: ([0-9a-f]{8}-[0-9a-f]{4}-4[0-9a-f]{3}-[89ab][0-9a-f]{3}-[0-9a-f]{12})\r\n").unwrap();
+
+        let mut first_request = request("/");
+        let mut second_request = request("/");
+        resource.assign_request_id(&mut first_request);
+        resource.assign_request_id(&mut second_request);
+
+        let first = String::from_utf8(resource.build_response(&first_request)).unwrap();
+        let first_id = id_regex.captures(&first).unwrap()[1].to_string();
+        assert_eq!(first_request.request_id, Some(first_id.clone()));
+
+        let second = String::from_utf8(resource.build_response(&second_request)).unwrap();
+        let second_id = id_regex.captures(&second).unwrap()[1].to_string();
+        assert_eq!(second_request.request_id, Some(second_id.clone()));
+        assert_eq!(resource.last_request_id(), Some(second_id.clone()));
+
+        assert_ne!(first_id, second_id);
+    }
+
+    #[test]
+    fn should_send_more_bytes_than_content_length() {
+        let resource = Resource::new("/");
+        resource.body("hello").overflow_body(5, "0123456789");
+
+        assert_eq!(response_for(&resource, "/"), "HTTP/1.1 200 Ok\r\nContent-Length: 5\r\n\r\nhello0123456789");
+    }
+
+    #[test]
+    fn should_match_content_type_ignoring_parameters() {
+        let resource = Resource::new("/");
+        resource.match_content_type("application/json");
+
+        let mut json = request("/");
+        json.headers.insert(String::from("content-type"), String::from("Application/JSON; charset=utf-8"));
+
+        let mut form = request("/");
+        form.headers.insert(String::from("Content-Type"), String::from("application/x-www-form-urlencoded"));
+
+        assert!(resource.matches_request(&json));
+        assert!(!resource.matches_request(&form));
+        assert!(!resource.matches_request(&request("/")));
+    }
+
+    #[test]
+    fn should_redirect_preserving_query() {
+        let resource = Resource::new("/old");
+        resource.redirect_preserving_query(Status::MovedPermanently, "/new");
+
+        assert_eq!(response_for(&resource, "/old?x=1&y=2"), "HTTP/1.1 301 Moved Permanently\r\nLocation: /new?x=1&y=2\r\nContent-Length: 0\r\n\r\n");
+        assert_eq!(response_for(&resource, "/old"), "HTTP/1.1 301 Moved Permanently\r\nLocation: /new\r\nContent-Length: 0\r\n\r\n");
+    }
+
+    #[test]
+    fn should_encode_initial_stream_body_as_chunk() {
+        let resource = Resource::new("/");
+        resource.stream().chunked().body("hello");
+
+        assert_eq!(response_for(&resource, "/"), "HTTP/1.1 200 Ok\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n");
+    }
+
+    #[test]
+    fn should_send_server_sent_events() {
+        let resource = Resource::new("/events");
+        let receiver = resource.stream().stream_receiver();
+
+        resource
+            .send_event(Some("update"), "line 1\nline 2", Some("7"))
+            .send_event(None, "plain", None);
+
+        assert_eq!(receiver.recv().unwrap(), "id: 7\nevent: update\ndata: line 1\ndata: line 2\n\n");
+        assert_eq!(receiver.recv().unwrap(), "data: plain\n\n");
+        assert!(resource.get_headers().contains("Content-Type: text/event-stream\r\n"));
+    }
+
+    #[test]
+    fn should_keep_content_type_when_sending_events() {
+        let resource = Resource::new("/events");
+        resource.stream().header("Content-Type", "text/plain").send_event(None, "data", None);
+
+        assert_eq!(resource.get_headers(), "Content-Type: text/plain\r\n");
+    }
+
+    #[test]
+    fn should_send_raw_chunks() {
+        let resource = Resource::new("/");
+        resource.body("ignored").raw_chunks(vec!(
+            (String::from("ZZ"), b"data".to_vec()),
+            (String::from("2"), b"more".to_vec()),
+            (String::from("0"), vec!())
+        ));
+
+        assert_eq!(
+            response_for(&resource, "/"),
+            "HTTP/1.1 200 Ok\r\nTransfer-Encoding: chunked\r\n\r\nZZ\r\ndata\r\n2\r\nmore\r\n0\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn should_build_headers_from_request() {
+        let resource = Resource::new("/");
+        resource
+            .header_fn_req("X-Signature", |request| Some(format!("sig-{}", request.body)))
+            .header_fn_req("X-Omitted", |_| None);
+
+        let mut request = request("/");
+        request.body = String::from("abc");
+
+        assert_eq!(
+            String::from_utf8(resource.build_response(&request)).unwrap(),
+            "HTTP/1.1 200 Ok\r\nX-Signature: sig-abc\r\nContent-Length: 0\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn should_identify_copies_of_same_resource() {
+        let resource = Resource::new("/");
+
+        assert!(resource.is_same(&resource.clone()));
+        assert!(!resource.is_same(&Resource::new("/")));
+    }
+
+    #[test]
+    fn should_count_wildcards_in_uri() {
+        assert_eq!(Resource::new("/users/me?filter=*").wildcard_count(), 0);
+        assert_eq!(Resource::new("/users/{id}").wildcard_count(), 1);
+        assert_eq!(Resource::new("/users/{id}/.*").wildcard_count(), 3);
+        assert_eq!(Resource::new("/files/.+").wildcard_count(), 2);
+    }
+
+    #[test]
+    fn should_not_count_literal_dots_as_wildcards() {
+        assert_eq!(Resource::new("/report.pdf").wildcard_count(), 0);
+        assert_eq!(Resource::new("/v1.2/{id}").wildcard_count(), 1);
+        assert_eq!(Resource::new(r"/report\.pdf").wildcard_count(), 0);
+        assert_eq!(Resource::new(r"/path\*").wildcard_count(), 0);
+    }
+
+    #[test]
+    fn should_strip_line_breaks_from_headers() {
+        let resource = Resource::new("/");
+        resource
+            .status(Status::Found)
+            .header("Location", "/next?a=1\r\nSet-Cookie: session=stolen")
+            .header("X-Bad\nName", "value");
+
+        let response = response_for(&resource, "/");
+
+        assert!(response.contains("Location: /next?a=1Set-Cookie: session=stolen\r\n"));
+        assert!(response.contains("X-BadName: value\r\n"));
+        assert!(!response.contains("\nSet-Cookie"));
+    }
+
+    #[test]
+    fn should_set_content_length_to_body_byte_length() {
+        let resource = Resource::new("/greet/{name}");
+        resource.body("Olá {path.name}!");
+
+        let stream = Resource::new("/");
+        stream.stream().body("data");
+
+        let custom = Resource::new("/");
+        custom.header("content-length", "2").body("hi");
+
+        assert_eq!(response_for(&resource, "/greet/José"), "HTTP/1.1 200 Ok\r\nContent-Length: 11\r\n\r\nOlá José!");
+        assert_eq!(response_for(&stream, "/"), "HTTP/1.1 200 Ok\r\n\r\ndata");
+        assert_eq!(response_for(&custom, "/"), "HTTP/1.1 200 Ok\r\ncontent-length: 2\r\n\r\nhi");
+    }
+
+    #[test]
+    fn should_build_grpc_message_body() {
+        let resource = Resource::new("/");
+        resource.grpc_message(&[0x08, 0x96, 0x01], false);
+
+        let mut expected = b"HTTP/1.1 200 Ok\r\nContent-Type: application/grpc-web+proto\r\nContent-Length: 8\r\n\r\n".to_vec();
+        expected.extend_from_slice(&[0, 0, 0, 0, 3, 0x08, 0x96, 0x01]);
+
+        assert_eq!(resource.build_response(&request("/")), expected);
+    }
+
+    #[test]
+    fn should_flag_compressed_grpc_message() {
+        let resource = Resource::new("/");
+        resource.grpc_message(b"compressed", true);
+
+        let response = resource.build_response(&request("/"));
+        let body = &response[response.len() - 15..];
+
+        assert_eq!(&body[..5], &[1, 0, 0, 0, 10]);
+        assert_eq!(&body[5..], b"compressed");
+    }
+
+    #[cfg(feature = "serde")]
+    #[derive(Deserialize)]
+    struct Sum { a: i32, b: i32 }
+
+    #[cfg(feature = "serde")]
+    #[derive(Serialize)]
+    struct SumResult { result: i32 }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn should_build_response_using_json_fn() {
+        let resource = Resource::new("/sum");
+        resource.json_fn(|sum: Sum| (Status::Created, SumResult { result: sum.a + sum.b }));
+
+        let mut request = request("/sum");
+        request.body = String::from(r#"{ "a": 2, "b": 3 }"#);
+
+        assert_eq!(
+            String::from_utf8(resource.build_response(&request)).unwrap(),
+            "HTTP/1.1 201 Created\r\nContent-Type: application/json\r\nContent-Length: 12\r\n\r\n{\"result\":5}"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn should_return_bad_request_when_json_fn_body_is_invalid() {
+        let resource = Resource::new("/sum");
+        resource.json_fn(|sum: Sum| (Status::OK, SumResult { result: sum.a + sum.b }));
+
+        let mut request = request("/sum");
+        request.body = String::from(r#"{ "a": "two" }"#);
+
+        let response = String::from_utf8(resource.build_response(&request)).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\nContent-Type: application/json\r\nContent-Length: "));
+        assert!(response.contains("\r\n\r\n{\"error\":"));
+    }
+
+    #[cfg(feature = "serde")]
+    struct Unserializable;
+
+    #[cfg(feature = "serde")]
+    impl Serialize for Unserializable {
+        fn serialize<S: serde::Serializer>(&self, _serializer: S) -> Result<S::Ok, S::Error> {
+            Err(serde::ser::Error::custom("not serializable"))
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn should_return_internal_server_error_when_json_fn_result_cannot_be_serialized() {
+        let resource = Resource::new("/sum");
+        resource.json_fn(|_: Sum| (Status::OK, Unserializable));
+
+        let mut request = request("/sum");
+        request.body = String::from(r#"{ "a": 2, "b": 3 }"#);
+
+        assert_eq!(
+            String::from_utf8(resource.build_response(&request)).unwrap(),
+            "HTTP/1.1 500 Internal Server Error\r\nContent-Type: application/json\r\nContent-Length: 0\r\n\r\n"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    #[should_panic(expected = "You can't define 'json_fn' when 'body' is already defined")]
+    fn should_fail_when_defining_json_fn_after_body() {
+        let resource = Resource::new("/sum");
+        resource.body("hello").json_fn(|sum: Sum| (Status::OK, SumResult { result: sum.a + sum.b }));
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    #[should_panic(expected = "You can't define 'body_fn' when 'json_fn' is already defined")]
+    fn should_fail_when_defining_body_fn_after_json_fn() {
+        let resource = Resource::new("/sum");
+        resource.json_fn(|sum: Sum| (Status::OK, SumResult { result: sum.a + sum.b })).body_fn(|_| String::new());
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn should_export_configuration_as_json() {
+        let resource = Resource::new("/user/{id}");
+        resource.status(Status::NoContent).header("Content-Type", "text").body("hello");
+
+        let any = Resource::new("/any");
+        any.any_method().body_fn(|_| String::from("dynamic"));
+
+        let config: serde_json::Value = serde_json::from_str(&resource.to_config_json()).unwrap();
+        let any_config: serde_json::Value = serde_json::from_str(&any.to_config_json()).unwrap();
+
+        assert_eq!(config, serde_json::json!({
+            "uri": "/user/{id}",
+            "methods": ["GET"],
+            "status": "204 No Content",
+            "headers": { "Content-Type": "text" },
+            "body": "hello"
+        }));
+        assert_eq!(any_config["methods"], serde_json::json!(["*"]));
+        assert_eq!(any_config["body"], serde_json::Value::Null);
+    }
+
     #[test]
     fn should_increment_request_count() {
         let resource = Resource::new("/");
         resource.body("hello!");
 
-        resource.increment_request_count();
-        resource.increment_request_count();
-        resource.increment_request_count();
+        resource.increment_request_count();
+        resource.increment_request_count();
+        resource.increment_request_count();
+
+        assert_eq!(resource.request_count(), 3);
+    }
+
+    #[test]
+    fn should_verify_no_body_expectation() {
+        let resource = Resource::new("/");
+        resource.expect_no_body();
+
+        resource.add_request(request("/"));
+        assert_eq!(resource.verify(), Ok(()));
+
+        let mut req = request("/");
+        req.body = String::from("unexpected");
+        resource.add_request(req);
+
+        assert_eq!(resource.verify(), Err(String::from("GET /: expected no body, but received \"unexpected\"")));
+    }
+
+    #[test]
+    fn should_verify_query_expectation() {
+        let resource = Resource::new("/users");
+        resource.expect_query("api_version", "2").expect_query("token", "*");
+
+        resource.add_request(request("/users?api_version=2&token=abc"));
+        assert_eq!(resource.verify(), Ok(()));
 
-        assert_eq!(resource.request_count(), 3);
+        resource.add_request(request("/users?api_version=1&token=abc"));
+        resource.add_request(request("/users?api_version=2"));
+
+        assert_eq!(resource.verify(), Err(String::from(
+            "GET /users?api_version=1&token=abc: expected query parameter \"api_version\" to be \"2\", but received \"1\"\n\
+             GET /users?api_version=2: expected query parameter \"token\""
+        )));
     }
 
     #[test]
@@ -701,6 +3058,51 @@ mod tests {
     }
 
 
+    #[test]
+    fn should_set_stream_chunk_size() {
+        let resource = Resource::new("/");
+        resource.stream().stream_chunk_size(64);
+
+        assert_eq!(resource.get_stream_chunk_size(), Some(64));
+    }
+
+    #[test]
+    fn should_omit_body_for_head_requests() {
+        let resource = Resource::new("/");
+        resource.header("Content-Length", "5").body("hello");
+
+        let mut head = request("/");
+        head.method = String::from("HEAD");
+        head.method_enum = Method::HEAD;
+
+        let response = String::from_utf8(resource.build_response(&head)).unwrap();
+        assert_eq!(response, "HTTP/1.1 200 Ok\r\nContent-Length: 5\r\n\r\n");
+    }
+
+    #[test]
+    fn should_build_initial_stream_body_from_request() {
+        let resource = Resource::new("/");
+        resource.stream().body("fresh").stream_initial_fn(|request| {
+            match request.headers.get("Last-Event-ID") {
+                Some(id) => format!("resume from {}", id.parse::<u32>().unwrap() + 1),
+                None => String::from("start")
+            }
+        });
+
+        let mut resume = request("/");
+        resume.headers.insert(String::from("Last-Event-ID"), String::from("10"));
+
+        assert!(response_for(&resource, "/").ends_with("\r\n\r\nstart"));
+        assert!(String::from_utf8(resource.build_response(&resume)).unwrap().ends_with("resume from 11"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Stream chunk size must be greater than zero")]
+    fn should_fail_when_stream_chunk_size_is_zero() {
+        let resource = Resource::new("/");
+        resource.stream_chunk_size(0);
+    }
+
     #[test]
     fn should_notify_data() {
         let resource = Resource::new("/");
@@ -753,6 +3155,17 @@ mod tests {
         assert_eq!(resource.open_connections_count(), 0);
     }
 
+    #[test]
+    fn should_send_binary_data() {
+        let resource = Resource::new("/");
+
+        let receiver = resource.stream_bytes_receiver();
+        resource.send_bytes(&[0, 159, 146, 150]).send("text");
+
+        assert_eq!(receiver.recv().unwrap(), vec!(0, 159, 146, 150));
+        assert_eq!(receiver.recv().unwrap(), b"text".to_vec());
+    }
+
     #[test]
     fn should_send_data_with_line_break() {
         let resource = Resource::new("/");
@@ -764,12 +3177,97 @@ mod tests {
         assert_eq!(receiver.recv().unwrap(), "again\n");
     }
 
+    #[test]
+    fn should_compute_latency_from_active_connections() {
+        let resource = Resource::new("/");
+        assert_eq!(resource.get_latency(3), None);
+
+        resource.latency_per_connection(Duration::from_millis(50), Duration::from_millis(100));
+
+        assert_eq!(resource.get_latency(1), Some(Duration::from_millis(50)));
+        assert_eq!(resource.get_latency(3), Some(Duration::from_millis(250)));
+    }
+
+    #[test]
+    fn should_pick_delay_from_range() {
+        let resource = Resource::new("/");
+        resource.delay_range(Duration::from_millis(100), Duration::from_millis(200));
+        let mut state = time_seed();
+
+        for _ in 0..100 {
+            let delay = resource.get_delay(|| splitmix64(&mut state)).unwrap();
+            assert!(delay >= Duration::from_millis(100) && delay <= Duration::from_millis(200));
+        }
+
+        assert_eq!(resource.get_delay(|| u64::MAX), Some(Duration::from_millis(100) + Duration::from_nanos(u64::MAX % 100_000_001)));
+    }
+
+    #[test]
+    fn should_pick_delay_from_range_as_long_as_nanoseconds_fit_u64() {
+        let resource = Resource::new("/");
+        resource.delay_range(Duration::from_secs(1), Duration::MAX);
+
+        assert_eq!(resource.get_delay(|| u64::MAX), Some(Duration::from_secs(1) + Duration::from_nanos(u64::MAX)));
+
+        resource.delay_range(Duration::from_secs(0), Duration::from_nanos(u64::MAX));
+
+        assert_eq!(resource.get_delay(|| u64::MAX), Some(Duration::from_nanos(u64::MAX)));
+    }
+
+    #[test]
+    fn should_prefer_fixed_delay_over_delay_range() {
+        let resource = Resource::new("/");
+        resource.delay_range(Duration::from_millis(100), Duration::from_millis(200));
+        resource.delay(Duration::from_millis(5));
+
+        assert_eq!(resource.get_delay(|| 0), Some(Duration::from_millis(5)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Delay range min must not be greater than max")]
+    fn should_fail_when_delay_range_min_is_greater_than_max() {
+        let resource = Resource::new("/");
+        resource.delay_range(Duration::from_millis(200), Duration::from_millis(100));
+    }
+
+    #[test]
+    #[should_panic(expected = "Throttle must be greater than zero")]
+    fn should_fail_when_throttle_is_zero() {
+        let resource = Resource::new("/");
+        resource.throttle(0);
+    }
+
     #[test]
     fn should_set_delay() {
         let resource = Resource::new("/");
         resource.delay(Duration::from_millis(200));
 
-        assert_eq!(resource.get_delay(), Some(Duration::from_millis(200)));
+        assert_eq!(resource.get_delay(|| 0), Some(Duration::from_millis(200)));
+    }
+
+    #[test]
+    fn should_match_any_method() {
+        let resource = Resource::new("/");
+        resource.any_method();
+
+        assert!(resource.matches_method("GET"));
+        assert!(resource.matches_method("POST"));
+        assert!(resource.matches_method("DELETE"));
+    }
+
+    #[test]
+    fn should_restrict_to_method_after_any_method() {
+        let resource = Resource::new("/");
+        resource.any_method().method(Method::PUT);
+
+        assert!(resource.matches_method("PUT"));
+        assert!(!resource.matches_method("GET"));
+    }
+
+    #[test]
+    fn should_expose_uri_pattern() {
+        let resource = Resource::new("/endpoint/{param1}/[0-9]+?query=*");
+        assert_eq!(resource.uri_pattern(), "/endpoint/(?P<param1>[^//|/?]+)/[0-9]+");
     }
 
     #[test]
@@ -778,6 +3276,93 @@ mod tests {
         assert!(resource.matches_uri("/some-endpoint"));
     }
 
+    #[test]
+    fn should_match_uri_ignoring_case() {
+        let resource = Resource::new("/users/{id}");
+        assert!(!resource.matches_uri("/Users/1"));
+
+        resource.case_insensitive();
+        assert!(resource.matches_uri("/Users/1"));
+        assert_eq!(resource.extra_path_params("/USERS/abc").get("id").unwrap(), "abc");
+    }
+
+    #[test]
+    fn should_match_uri_ignoring_trailing_slash() {
+        let resource = Resource::new("/users/");
+        assert!(!resource.matches_uri("/users"));
+
+        resource.ignore_trailing_slash();
+        assert!(resource.matches_uri("/users"));
+        assert!(resource.matches_uri("/users/"));
+        assert!(resource.matches_uri("/users?page=1"));
+    }
+
+    #[test]
+    fn should_fail_to_create_resource_with_invalid_regex() {
+        let error = Resource::try_new("/files/(unclosed").err().unwrap();
+
+        assert!(error.to_string().starts_with("Invalid resource URI '/files/(unclosed': "));
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid resource URI '/files/(unclosed'")]
+    fn should_panic_with_invalid_uri_message() {
+        Resource::new("/files/(unclosed");
+    }
+
+    #[test]
+    fn should_match_regex_uri_as_is() {
+        let resource = Resource::new_regex("^/(?<g>.*)/(?<a>.*)/maven-metadata.xml$");
+        resource.body("{path.g} {path.a}");
+
+        assert!(resource.matches_uri("/org/example/app/maven-metadata.xml"));
+        assert!(!resource.matches_uri("/org/example/app/maven-metadata.xml?version=1"));
+        assert!(!resource.matches_uri("/mirror/maven-metadata.xml"));
+        assert_eq!(
+            response_for(&resource, "/org/example/app/maven-metadata.xml"),
+            "HTTP/1.1 200 Ok\r\nContent-Length: 15\r\n\r\norg/example app"
+        );
+    }
+
+    #[test]
+    fn should_not_replace_braces_in_regex_uri() {
+        let resource = Resource::new_regex("^/items/[0-9]{2}$");
+
+        assert!(resource.matches_uri("/items/42"));
+        assert!(!resource.matches_uri("/items/4"));
+    }
+
+    #[test]
+    fn should_match_literal_uri_exactly() {
+        let resource = Resource::new_literal("/files/report.pdf");
+        assert!(resource.matches_uri("/files/report.pdf"));
+        assert!(!resource.matches_uri("/files/reportXpdf"));
+        assert!(!resource.matches_uri("/files/report.pdf/more"));
+
+        let resource = Resource::new_literal("/c++/a+b");
+        assert!(resource.matches_uri("/c++/a+b"));
+        assert!(!resource.matches_uri("/c/aab"));
+    }
+
+    #[test]
+    fn should_match_literal_uri_with_query_and_path_params() {
+        let resource = Resource::new_literal("/files/{name}.json?version=*");
+        assert!(resource.matches_uri("/files/report.json?version=2"));
+        assert!(!resource.matches_uri("/files/report.json"));
+        assert!(!resource.matches_uri("/files/reportXjson?version=2"));
+        assert_eq!(resource.extra_path_params("/files/report.json?version=2").get("name").unwrap(), "report");
+    }
+
+    #[test]
+    fn should_match_literal_uri_ignoring_trailing_slash() {
+        let resource = Resource::new_literal("/users/");
+        resource.ignore_trailing_slash();
+
+        assert!(resource.matches_uri("/users"));
+        assert!(resource.matches_uri("/users/?page=1"));
+        assert!(!resource.matches_uri("/users/1"));
+    }
+
     #[test]
     fn should_not_match_uri_when_uri_does_not_match() {
         let resource = Resource::new("/some-endpoint");
@@ -791,6 +3376,37 @@ mod tests {
         assert!(resource.matches_uri("/endpoint/123-345/some/abc"));
     }
 
+    #[test]
+    fn should_match_uri_with_optional_path_param() {
+        let resource = Resource::new("/items/{id?}");
+
+        assert!(resource.matches_uri("/items"));
+        assert!(resource.matches_uri("/items/5"));
+        assert_eq!(resource.extra_path_params("/items/5").get("id").unwrap(), "5");
+        assert!(!resource.extra_path_params("/items").contains_key("id"));
+    }
+
+    #[test]
+    fn should_match_uri_with_catch_all_path_param() {
+        let resource = Resource::new("/static/{path*}?v=*");
+        resource.body("{path.path}");
+
+        assert!(resource.matches_uri("/static/a/b/c?v=1"));
+        assert_eq!(resource.extra_path_params("/static/a/b/c?v=1").get("path").unwrap(), "a/b/c");
+        assert_eq!(response_for(&resource, "/static/a/b/c.css?v=1"), "HTTP/1.1 200 Ok\r\nContent-Length: 9\r\n\r\na/b/c.css");
+    }
+
+    #[test]
+    fn should_match_literal_uri_with_optional_and_catch_all_path_params() {
+        let resource = Resource::new_literal("/items/{id?}");
+        assert!(resource.matches_uri("/items"));
+        assert!(resource.matches_uri("/items/5"));
+        assert!(!resource.matches_uri("/items/5/6"));
+
+        let resource = Resource::new_literal("/static/{path*}");
+        assert_eq!(resource.extra_path_params("/static/a/b.c").get("path").unwrap(), "a/b.c");
+    }
+
     #[test]
     fn should_not_match_uri_with_path_params_when_uri_does_not_match() {
         let resource = Resource::new("/endpoint/{param1}/some/{param2}");
@@ -847,12 +3463,34 @@ mod tests {
         assert!(resource.matches_uri("/endpoint?hello=1234"));
     }
 
+    #[test]
+    fn should_match_request_with_header_regex() {
+        let resource = Resource::new("/");
+        resource.match_header_regex("Authorization", "Bearer .+");
+
+        let mut req = request("/");
+        req.headers.insert(String::from("authorization"), String::from("Bearer abc"));
+        assert!(resource.matches_request(&req));
+
+        req.headers.insert(String::from("authorization"), String::from("Basic abc"));
+        assert!(!resource.matches_request(&req));
+
+        assert!(!resource.matches_request(&request("/")));
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid header pattern '(unclosed'")]
+    fn should_fail_when_header_pattern_is_invalid() {
+        let resource = Resource::new("/");
+        resource.match_header_regex("Authorization", "(unclosed");
+    }
+
     #[test]
     fn should_build_response() {
         let resource = Resource::new("/");
         resource.status(Status::NotFound);
 
-        assert_eq!(resource.build_response("/"), "HTTP/1.1 404 Not Found\r\n\r\n");
+        assert_eq!(response_for(&resource, "/"), "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n");
     }
 
     #[test]
@@ -860,7 +3498,206 @@ mod tests {
         let resource = Resource::new("/");
         resource.status(Status::Accepted).body("hello!");
 
-        assert_eq!(resource.build_response("/"), "HTTP/1.1 202 Accepted\r\n\r\nhello!");
+        assert_eq!(response_for(&resource, "/"), "HTTP/1.1 202 Accepted\r\nContent-Length: 6\r\n\r\nhello!");
+    }
+
+    #[test]
+    #[cfg(feature = "brotli")]
+    fn should_compress_body_with_brotli_when_accepted() {
+        use std::io::Read;
+
+        let resource = Resource::new("/");
+        resource.body("compress me, compress me, compress me!").brotli();
+
+        let mut accepts_br = request("/");
+        accepts_br.headers.insert(String::from("Accept-Encoding"), String::from("gzip, br"));
+
+        let mut rejects_br = request("/");
+        rejects_br.headers.insert(String::from("Accept-Encoding"), String::from("gzip, br;q=0"));
+
+        let response = resource.build_response(&accepts_br);
+        let head_end = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        let head = String::from_utf8(response[..head_end].to_vec()).unwrap();
+
+        let mut body = String::new();
+        brotli::Decompressor::new(&response[head_end..], 4096).read_to_string(&mut body).unwrap();
+
+        assert!(head.contains("Content-Encoding: br\r\n"));
+        assert!(head.contains("Vary: Accept-Encoding\r\n"));
+        assert!(head.contains(&format!("Content-Length: {}\r\n", response.len() - head_end)));
+        assert_eq!(body, "compress me, compress me, compress me!");
+        assert_eq!(
+            response_for(&resource, "/"),
+            "HTTP/1.1 200 Ok\r\nVary: Accept-Encoding\r\nContent-Length: 38\r\n\r\ncompress me, compress me, compress me!"
+        );
+        assert_eq!(
+            String::from_utf8(resource.build_response(&rejects_br)).unwrap(),
+            "HTTP/1.1 200 Ok\r\nVary: Accept-Encoding\r\nContent-Length: 38\r\n\r\ncompress me, compress me, compress me!"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "brotli")]
+    fn should_not_compress_raw_chunks_with_brotli() {
+        let resource = Resource::new("/");
+        resource.brotli().raw_chunks(vec!((String::from("4"), b"data".to_vec()), (String::from("0"), vec!())));
+
+        let mut accepts_br = request("/");
+        accepts_br.headers.insert(String::from("Accept-Encoding"), String::from("br"));
+
+        assert_eq!(
+            String::from_utf8(resource.build_response(&accepts_br)).unwrap(),
+            "HTTP/1.1 200 Ok\r\nTransfer-Encoding: chunked\r\n\r\n4\r\ndata\r\n0\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn should_build_response_with_body_from_file() {
+        let path = std::env::temp_dir().join(format!("http-test-server-body-{}.json", generate_request_id()));
+        fs::write(&path, "{\"id\": 1}").unwrap();
+
+        let resource = Resource::new("/");
+        resource.body_from_file(&path);
+
+        assert_eq!(response_for(&resource, "/"), "HTTP/1.1 200 Ok\r\nContent-Length: 9\r\n\r\n{\"id\": 1}");
+
+        fs::write(&path, "{\"id\": 22}").unwrap();
+        assert_eq!(response_for(&resource, "/"), "HTTP/1.1 200 Ok\r\nContent-Length: 10\r\n\r\n{\"id\": 22}");
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn should_respond_with_internal_server_error_when_body_file_does_not_exist() {
+        let path = std::env::temp_dir().join(format!("http-test-server-body-{}.json", generate_request_id()));
+
+        let resource = Resource::new("/");
+        resource.body_from_file(&path);
+
+        assert_eq!(response_for(&resource, "/"), "HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n");
+
+        fs::write(&path, "{\"id\": 1}").unwrap();
+        assert_eq!(response_for(&resource, "/"), "HTTP/1.1 200 Ok\r\nContent-Length: 9\r\n\r\n{\"id\": 1}");
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn should_respond_with_response_sequence() {
+        let resource = Resource::new("/");
+        resource.body("ignored").responses(&[
+            (Status::ServiceUnavailable, ""),
+            (Status::ServiceUnavailable, "retry"),
+            (Status::OK, "done")
+        ]);
+
+        let mut statuses = vec!();
+
+        for _ in 0..4 {
+            statuses.push(response_for(&resource, "/"));
+        }
+
+        assert_eq!(statuses, vec!(
+            "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n",
+            "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 5\r\n\r\nretry",
+            "HTTP/1.1 200 Ok\r\nContent-Length: 4\r\n\r\ndone",
+            "HTTP/1.1 200 Ok\r\nContent-Length: 4\r\n\r\ndone"
+        ));
+    }
+
+    #[test]
+    fn should_restart_response_sequence_when_redefined() {
+        let resource = Resource::new("/");
+        resource.responses(&[(Status::ServiceUnavailable, ""), (Status::OK, "")]);
+
+        assert!(response_for(&resource, "/").starts_with("HTTP/1.1 503"));
+
+        resource.responses(&[(Status::Accepted, ""), (Status::OK, "")]);
+
+        assert!(response_for(&resource, "/").starts_with("HTTP/1.1 202"));
+        assert!(response_for(&resource, "/").starts_with("HTTP/1.1 200"));
+    }
+
+    #[test]
+    fn should_build_response_using_try_body_fn() {
+        let resource = Resource::new("/");
+        resource.status(Status::Accepted).try_body_fn(|request| {
+            match request.headers.get("X-Upstream") {
+                Some(upstream) => Ok(format!("via {}", upstream)),
+                None => Err((Status::BadGateway, String::from("upstream down")))
+            }
+        });
+
+        let mut proxied = request("/");
+        proxied.headers.insert(String::from("X-Upstream"), String::from("a"));
+
+        assert_eq!(response_for(&resource, "/"), "HTTP/1.1 502 Bad Gateway\r\nContent-Length: 13\r\n\r\nupstream down");
+        assert_eq!(
+            String::from_utf8(resource.build_response(&proxied)).unwrap(),
+            "HTTP/1.1 202 Accepted\r\nContent-Length: 5\r\n\r\nvia a"
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "You can't define 'try_body_fn' when 'body' is already defined")]
+    fn should_fail_when_defining_try_body_fn_after_body() {
+        let resource = Resource::new("/");
+        resource.body("hello").try_body_fn(|_| Ok(String::new()));
+    }
+
+    #[test]
+    #[should_panic(expected = "You can't define 'body' when 'try_body_fn' is already defined")]
+    fn should_fail_when_defining_body_after_try_body_fn() {
+        let resource = Resource::new("/");
+        resource.try_body_fn(|_| Ok(String::new())).body("hello");
+    }
+
+    #[test]
+    fn should_build_response_using_body_fn_req() {
+        let resource = Resource::new("/");
+        resource.body_fn_req(|request| format!("{} {} {}", request.method, request.url, request.version));
+
+        assert_eq!(response_for(&resource, "/"), "HTTP/1.1 200 Ok\r\nContent-Length: 14\r\n\r\nGET / HTTP/1.1");
+    }
+
+    #[test]
+    fn should_fail_when_mixing_body_definitions_in_any_order() {
+        type Definition = fn(&Resource);
+        let definitions: Vec<(&str, Definition)> = vec!(
+            ("body", |resource| { resource.body("hello"); }),
+            ("body_bytes", |resource| { resource.body_bytes(vec!(1, 2)); }),
+            ("body_from_file", |resource| { resource.body_from_file("/some/file"); }),
+            ("grpc_message", |resource| { resource.grpc_message(&[1, 2], false); }),
+            ("body_fn", |resource| { resource.body_fn(|_| String::new()); }),
+            ("try_body_fn", |resource| { resource.try_body_fn(|_| Ok(String::new())); }),
+            ("body_fn_req", |resource| { resource.body_fn_req(|_| String::new()); })
+        );
+        let source = |name: &str| match name {
+            "body_fn" => BodySource::Builder,
+            "try_body_fn" => BodySource::TryBuilder,
+            "body_fn_req" => BodySource::RequestBuilder,
+            _ => BodySource::Content
+        };
+
+        for &(first, define_first) in &definitions {
+            for &(second, define_second) in &definitions {
+                let resource = Resource::new("/");
+                define_first(&resource);
+
+                let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| define_second(&resource)));
+
+                assert_eq!(result.is_err(), source(first) != source(second), "'{}' then '{}'", first, second);
+            }
+        }
+    }
+
+    #[test]
+    fn should_build_response_with_owned_body() {
+        let resource = Resource::new("/");
+        let name = String::from("world");
+        resource.body(format!("hello {}!", name));
+
+        assert_eq!(response_for(&resource, "/"), "HTTP/1.1 200 Ok\r\nContent-Length: 12\r\n\r\nhello world!");
     }
 
     #[test]
@@ -868,7 +3705,31 @@ mod tests {
         let resource = Resource::new("/endpoint/{param1}/{param2}");
         resource.status(Status::Accepted).body("Hello: {path.param2} {path.param1}");
 
-        assert_eq!(resource.build_response("/endpoint/123/abc"), "HTTP/1.1 202 Accepted\r\n\r\nHello: abc 123");
+        assert_eq!(response_for(&resource, "/endpoint/123/abc"), "HTTP/1.1 202 Accepted\r\nContent-Length: 14\r\n\r\nHello: abc 123");
+    }
+
+    #[test]
+    fn should_build_response_with_regex_named_groups() {
+        let resource = Resource::new("/maven2/(?<group_id>.+)/(?P<artifact_id>[^/]+)/maven-metadata.xml");
+        resource.body("<groupId>{path.group_id}</groupId><artifactId>{path.artifact_id}</artifactId>");
+
+        assert_eq!(
+            response_for(&resource, "/maven2/org/example/app/maven-metadata.xml"),
+            "HTTP/1.1 200 Ok\r\nContent-Length: 58\r\n\r\n<groupId>org/example</groupId><artifactId>app</artifactId>"
+        );
+    }
+
+    #[test]
+    fn should_pass_regex_named_groups_to_body_fn() {
+        let resource = Resource::new("/maven2/(?<group_id>.+)/{artifact_id}/maven-metadata.xml");
+        resource.body_fn(|params| {
+            format!("{} {}", params.path.get("group_id").unwrap(), params.path.get("artifact_id").unwrap())
+        });
+
+        assert_eq!(
+            response_for(&resource, "/maven2/org/example/app/maven-metadata.xml"),
+            "HTTP/1.1 200 Ok\r\nContent-Length: 15\r\n\r\norg/example app"
+        );
     }
 
     #[test]
@@ -876,7 +3737,67 @@ mod tests {
         let resource = Resource::new("/endpoint/{param1}?param2=111");
         resource.status(Status::Accepted).body("Hello: {query.param2} {path.param1}");
 
-        assert_eq!(resource.build_response("/endpoint/123?param2=111"), "HTTP/1.1 202 Accepted\r\n\r\nHello: 111 123");
+        assert_eq!(response_for(&resource, "/endpoint/123?param2=111"), "HTTP/1.1 202 Accepted\r\nContent-Length: 14\r\n\r\nHello: 111 123");
+    }
+
+    #[test]
+    fn should_decode_path_and_query_parameters() {
+        let resource = Resource::new("/user/{userId}/{folder}?q=*");
+        resource.body("{path.userId}|{path.folder}|{query.q}");
+
+        assert_eq!(
+            response_for(&resource, "/user/John%20Doe/a%2Fb+c?q=caf%C3%A9+au+lait%2B"),
+            "HTTP/1.1 200 Ok\r\nContent-Length: 29\r\n\r\nJohn Doe|a/b+c|caf\u{e9} au lait+"
+        );
+    }
+
+    #[test]
+    fn should_keep_last_value_of_repeated_query_parameters() {
+        let params = extract_query_params("/items?a=1&a=2");
+        assert_eq!(params.get("a").unwrap(), "2");
+        assert_eq!(params.len(), 1);
+    }
+
+    #[test]
+    fn should_extract_query_parameter_values_containing_equal_sign() {
+        let params = extract_query_params("/auth?token=x=y&next=");
+        assert_eq!(params.get("token").unwrap(), "x=y");
+        assert_eq!(params.get("next").unwrap(), "");
+    }
+
+    #[test]
+    fn should_extract_query_parameters_without_value() {
+        let params = extract_query_params("/items?flag&&page=2");
+        assert_eq!(params.get("flag").unwrap(), "");
+        assert_eq!(params.get("page").unwrap(), "2");
+        assert_eq!(params.len(), 2);
+
+        assert!(extract_query_params("/items").is_empty());
+    }
+
+    #[test]
+    fn should_not_match_uri_with_absent_query_parameter() {
+        let resource = Resource::new("/items");
+        resource.query_absent("debug");
+
+        assert!(resource.matches_uri("/items?page=1"));
+        assert!(!resource.matches_uri("/items?debug=1"));
+        assert!(!resource.matches_uri("/items?page=1&debug"));
+
+        resource.query("debug", "*");
+        assert!(resource.matches_uri("/items?debug=1"));
+    }
+
+    #[test]
+    fn should_match_uri_with_query_parameter_without_value() {
+        let resource = Resource::new("/items?flag");
+        assert!(resource.matches_uri("/items?page=1&flag"));
+        assert!(!resource.matches_uri("/items?page=1"));
+    }
+
+    #[test]
+    fn should_keep_invalid_percent_escapes() {
+        assert_eq!(percent_decode("100%25%zz%", false), "100%%zz%");
     }
 
     #[test]
@@ -884,7 +3805,7 @@ mod tests {
         let resource = Resource::new("/endpoint/{param1}?param2=111&param3=*");
         resource.status(Status::Accepted).body("Hello: {query.param3}");
 
-        assert_eq!(resource.build_response("/endpoint/123?param2=111&param3=banana"), "HTTP/1.1 202 Accepted\r\n\r\nHello: banana");
+        assert_eq!(response_for(&resource, "/endpoint/123?param2=111&param3=banana"), "HTTP/1.1 202 Accepted\r\nContent-Length: 13\r\n\r\nHello: banana");
     }
 
     #[test]
@@ -894,7 +3815,24 @@ mod tests {
             format!("Hello: {} {}", params.path.get("param2").unwrap(), params.path.get("param1").unwrap())
         });
 
-        assert_eq!(resource.build_response("/endpoint/123/abc"), "HTTP/1.1 202 Accepted\r\n\r\nHello: abc 123");
+        assert_eq!(response_for(&resource, "/endpoint/123/abc"), "HTTP/1.1 202 Accepted\r\nContent-Length: 14\r\n\r\nHello: abc 123");
+    }
+
+    #[test]
+    fn should_build_response_using_request_headers_and_body_in_body_fn() {
+        let resource = Resource::new("/echo");
+        resource.body_fn(|params| {
+            format!("{} {}", params.headers.get("X-Echo").unwrap(), params.body)
+        });
+
+        let mut request = request("/echo");
+        request.headers.insert(String::from("X-Echo"), String::from("header"));
+        request.body = String::from("body");
+
+        assert_eq!(
+            String::from_utf8(resource.build_response(&request)).unwrap(),
+            "HTTP/1.1 200 Ok\r\nContent-Length: 11\r\n\r\nheader body"
+        );
     }
 
     #[test]
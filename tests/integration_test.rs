@@ -18,10 +18,11 @@ fn test_defaults() {
     let response = request(server.port(), "/defaults", "GET");
     let request_data = requests.recv().unwrap();
 
-    assert_eq!(response, "HTTP/1.1 200 Ok\r\n\r\n");
+    assert_eq!(response, "HTTP/1.1 200 Ok\r\nConnection: close\r\nContent-Length: 0\r\n\r\n");
 
     assert_eq!(request_data.url, "/defaults");
     assert_eq!(request_data.method, "GET");
+    assert_eq!(request_data.method_enum, Method::GET);
     assert_eq!(request_data.headers, HashMap::new());
 
     assert_eq!(resource.request_count(), 1);
@@ -40,7 +41,178 @@ fn test_post_request() {
 
     let response = request(server.port(), "/create", "POST");
 
-    assert_eq!(response, "HTTP/1.1 201 Created\r\nContent-Type: text\r\n\r\nEverything is fine!");
+    assert_eq!(response, "HTTP/1.1 201 Created\r\nConnection: close\r\nContent-Type: text\r\nContent-Length: 19\r\n\r\nEverything is fine!");
+}
+
+#[test]
+fn test_request_body() {
+    let server = TestServer::new().unwrap();
+    server.create_resource("/create").method(Method::POST);
+    let requests = server.requests();
+
+    let host = format!("localhost:{}", server.port());
+    let mut stream = TcpStream::connect(host).unwrap();
+    stream.write_all(b"POST /create HTTP/1.1\r\nContent-Length: 12\r\n\r\n{\"name\":\"x\"}").unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let request_data = requests.recv().unwrap();
+
+    assert_eq!(response, "HTTP/1.1 200 Ok\r\nConnection: close\r\nContent-Length: 0\r\n\r\n");
+    assert_eq!(request_data.body, "{\"name\":\"x\"}");
+
+    request(server.port(), "/create", "POST");
+    assert_eq!(requests.recv().unwrap().body, "");
+}
+
+#[test]
+fn test_response_sequence() {
+    let server = TestServer::new().unwrap();
+    let resource = server.create_resource("/flaky");
+
+    resource.responses(&[
+        (Status::ServiceUnavailable, ""),
+        (Status::ServiceUnavailable, ""),
+        (Status::OK, "ok")
+    ]);
+
+    let statuses: Vec<String> = (0..3)
+        .map(|_| request(server.port(), "/flaky", "GET"))
+        .map(|response| response.lines().next().unwrap().to_string())
+        .collect();
+
+    assert_eq!(statuses, vec!("HTTP/1.1 503 Service Unavailable", "HTTP/1.1 503 Service Unavailable", "HTTP/1.1 200 Ok"));
+}
+
+#[test]
+fn test_binary_body() {
+    let server = TestServer::new().unwrap();
+    let png = vec!(0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A, 0x00, 0xFF, 0xFE);
+
+    server.create_resource("/image.png")
+        .header("Content-Type", "image/png")
+        .body_bytes(png.clone());
+
+    let mut stream = open_stream(server.port(), "/image.png", "GET");
+    let mut response = vec!();
+    stream.read_to_end(&mut response).unwrap();
+
+    let expected_head = b"HTTP/1.1 200 Ok\r\nConnection: close\r\nContent-Type: image/png\r\nContent-Length: 11\r\n\r\n";
+
+    assert_eq!(&response[..expected_head.len()], &expected_head[..]);
+    assert_eq!(&response[expected_head.len()..], &png[..]);
+}
+
+#[test]
+fn test_request_by_body() {
+    let server = TestServer::new().unwrap();
+    let user = server.create_resource("/graphql");
+    let posts = server.create_resource("/graphql");
+
+    user.method(Method::POST).match_body("GetUser").body("user");
+    posts.method(Method::POST).match_body_fn(|body| body.contains("GetPosts")).body("posts");
+
+    let host = format!("localhost:{}", server.port());
+    let graphql = |query: &str| {
+        let mut stream = TcpStream::connect(&host).unwrap();
+        let request = format!("POST /graphql HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}", query.len(), query);
+        stream.write_all(request.as_bytes()).unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+
+    assert!(graphql(r#"{"query": "query GetPosts { posts { id } }"}"#).ends_with("\r\n\r\nposts"));
+    assert!(graphql(r#"{"query": "query GetUser { user { id } }"}"#).ends_with("\r\n\r\nuser"));
+    assert!(graphql(r#"{"query": "query GetComments { comments { id } }"}"#).starts_with("HTTP/1.1 404 Not Found"));
+    assert_eq!(user.request_count(), 1);
+    assert_eq!(posts.request_count(), 1);
+}
+
+#[test]
+fn test_request_by_content_type() {
+    let server = TestServer::new().unwrap();
+    let json = server.create_resource("/login");
+    let form = server.create_resource("/login");
+
+    json.method(Method::POST).match_content_type("application/json").body("json");
+    form.method(Method::POST).match_content_type("application/x-www-form-urlencoded").body("form");
+
+    let host = format!("localhost:{}", server.port());
+
+    let mut stream = TcpStream::connect(&host).unwrap();
+    stream.write_all(b"POST /login HTTP/1.1\r\nContent-Type: application/json; charset=utf-8\r\nContent-Length: 2\r\n\r\n{}").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+
+    assert!(response.ends_with("\r\n\r\njson"));
+
+    let mut stream = TcpStream::connect(&host).unwrap();
+    stream.write_all(b"POST /login HTTP/1.1\r\nContent-Type: application/x-www-form-urlencoded\r\nContent-Length: 3\r\n\r\na=1").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+
+    assert!(response.ends_with("\r\n\r\nform"));
+    assert_eq!(json.request_count(), 1);
+    assert_eq!(form.request_count(), 1);
+}
+
+#[test]
+fn test_request_with_unknown_method() {
+    let server = TestServer::new().unwrap();
+    let requests = server.requests();
+
+    request(server.port(), "/files", "PROPFIND");
+    let request_data = requests.recv().unwrap();
+
+    assert_eq!(request_data.method, "PROPFIND");
+    assert_eq!(request_data.method_enum, Method::Other(String::from("PROPFIND")));
+}
+
+#[test]
+fn test_any_method() {
+    let server = TestServer::new().unwrap();
+    let resource = server.create_resource("/mock-everything");
+
+    resource.any_method().body("Same answer");
+
+    for method in &["GET", "POST", "DELETE"] {
+        let response = request(server.port(), "/mock-everything", method);
+
+        assert_eq!(response, "HTTP/1.1 200 Ok\r\nConnection: close\r\nContent-Length: 11\r\n\r\nSame answer");
+    }
+
+    assert_eq!(resource.request_count(), 3);
+}
+
+#[test]
+fn test_head_request() {
+    let server = TestServer::new().unwrap();
+    let resource = server.create_resource("/head");
+
+    resource
+        .header("Content-Length", "19")
+        .body("Everything is fine!");
+
+    let response = request(server.port(), "/head", "HEAD");
+
+    assert_eq!(response, "HTTP/1.1 200 Ok\r\nConnection: close\r\nContent-Length: 19\r\n\r\n");
+    assert_eq!(resource.request_count(), 1);
+}
+
+#[test]
+fn test_malformed_request_line() {
+    let server = TestServer::new().unwrap();
+
+    let host = format!("localhost:{}", server.port());
+    let mut stream = TcpStream::connect(host).unwrap();
+    stream.write_all(b"GARBAGE\r\n\r\n").unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+
+    assert_eq!(response, "HTTP/1.1 400 Bad Request\r\nConnection: close\r\nContent-Length: 0\r\n\r\n");
 }
 
 #[test]
@@ -85,7 +257,7 @@ fn test_request_with_path_and_query_params() {
 
     assert_eq!(
         response,
-        "HTTP/1.1 200 Ok\r\nContent-Type: application/json\r\n\r\n{\"id\": 123, \"userId\": \"superUser\", \"filter\": \"all\", \"v\": 1}"
+        "HTTP/1.1 200 Ok\r\nConnection: close\r\nContent-Type: application/json\r\nContent-Length: 59\r\n\r\n{\"id\": 123, \"userId\": \"superUser\", \"filter\": \"all\", \"v\": 1}"
     );
 }
 
@@ -98,7 +270,7 @@ fn test_request_to_regex_uri() {
     let response = request(server.port(), "/hello/2/b/goodbye", "GET");
     let request_data = requests.recv().unwrap();
 
-    assert_eq!(response, "HTTP/1.1 200 Ok\r\n\r\n");
+    assert_eq!(response, "HTTP/1.1 200 Ok\r\nConnection: close\r\nContent-Length: 0\r\n\r\n");
 
     assert_eq!(request_data.url, "/hello/2/b/goodbye");
     assert_eq!(request_data.method, "GET");
@@ -115,17 +287,35 @@ fn request_to_loopback_ip() {
     let host = format!("127.0.0.1:{}", server.port());
     let mut stream = TcpStream::connect(host).unwrap();
 
-    stream.write("GET /hello HTTP/1.1\r\n\r\n".as_bytes()).unwrap();
+    stream.write_all("GET /hello HTTP/1.1\r\n\r\n".as_bytes()).unwrap();
     stream.flush().unwrap();
 
     let mut reader = BufReader::new(stream);
     let mut response = String::new();
     reader.read_to_string(&mut response).unwrap();
 
-    assert_eq!(response, "HTTP/1.1 200 Ok\r\n\r\n");
+    assert_eq!(response, "HTTP/1.1 200 Ok\r\nConnection: close\r\nContent-Length: 0\r\n\r\n");
     assert_eq!(resource.request_count(), 1);
 }
 
+#[test]
+fn test_resources_match_uri_and_method_together() {
+    let server = TestServer::new().unwrap();
+    let resource_a = server.create_resource("/a");
+    let resource_b = server.create_resource("/b");
+
+    resource_a.method(Method::GET).body("a");
+    resource_b.method(Method::POST).body("b");
+
+    assert_eq!(request(server.port(), "/a", "GET"), "HTTP/1.1 200 Ok\r\nConnection: close\r\nContent-Length: 1\r\n\r\na");
+    assert_eq!(request(server.port(), "/b", "POST"), "HTTP/1.1 200 Ok\r\nConnection: close\r\nContent-Length: 1\r\n\r\nb");
+    assert!(request(server.port(), "/a", "POST").starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+    assert!(request(server.port(), "/b", "GET").starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+
+    assert_eq!(resource_a.request_count(), 1);
+    assert_eq!(resource_b.request_count(), 1);
+}
+
 
 fn request(port: u16, uri: &str, method: &str) -> String {
     let stream = open_stream(port, uri, method);
@@ -146,7 +336,7 @@ fn open_stream(port: u16, uri: &str, method: &str) -> TcpStream {
         uri
     );
 
-    stream.write(request.as_bytes()).unwrap();
+    stream.write_all(request.as_bytes()).unwrap();
     stream.flush().unwrap();
 
     stream